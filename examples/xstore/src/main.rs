@@ -17,24 +17,26 @@ fn main() -> Result<(), std::io::Error> {
         println!("Got error: {}", e);
     };
 
-    if let Err(e) = xsh.create_watch("backend/i2c", "backend/i2c") {
-        println!("Got error: {}", e);
-    }
+    let backend = xsh.domain_backends(0)?.join("i2c")?;
+    let watch = xsh.create_watch(&backend, &backend)?;
 
-    if let Err(e) = xsh.read_str("/local/domain/1/device/i2c/0") {
+    let device = xsh.domain_devices(1)?.join("i2c/0")?;
+
+    if let Err(e) = xsh.read_str(&device) {
         println!("Got error: {}", e);
     }
 
-    if let Err(e) = xsh.write_str("/local/domain/1/device/i2c/0", "that") {
+    if let Err(e) = xsh.write_str(&device, "that") {
         println!("Got error: {}", e);
     }
 
-    if let Err(e) = xsh.read_str("/local/domain/1/device/i2c/0") {
+    if let Err(e) = xsh.read_str(&device) {
         println!("Got error: {}", e);
     }
 
-    if let Err(e) = xsh.read_watch(0) {
-        println!("Got error: {}", e);
+    match watch.read_watch() {
+        Ok(event) => println!("Got event: {:?}", event),
+        Err(e) => println!("Got error: {}", e),
     }
 
     Ok(())
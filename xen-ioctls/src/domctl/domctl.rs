@@ -82,3 +82,37 @@ pub fn xc_domain_info(first_domain: u16, max_domain: u32) -> Vec<XcDominfo> {
 
     vec
 }
+
+/*
+ * Information about `domain` only, None if it doesn't exist.  Unlike
+ * xc_domain_info() errors are returned rather than printed.
+ */
+pub fn xc_domain_getinfo(domain: u16) -> Result<Option<XcDominfo>, std::io::Error> {
+    let mut domctl = XenDomctl {
+        cmd: XEN_DOMCTL_getdomaininfo,
+        interface_version: XEN_DOMCTL_INTERFACE_VERSION,
+        domain,
+        pad: [0; 3],
+        u: XenDomctlPayload {
+            domaininfo: XenDomctlGetDomainInfo::default(),
+        },
+    };
+
+    match do_domctl(&mut domctl) {
+        Ok(()) => (),
+        /* No domain at or above `domain` */
+        Err(e) if e.raw_os_error() == Some(libc::ESRCH) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    // SAFETY: domctl was successful and the union is a XenDomctlPayload variant
+    let info = unsafe { domctl.u.domaininfo };
+    /* Xen reports the next domain up when `domain` is gone */
+    if info.domain != domain {
+        return Ok(None);
+    }
+
+    XcDominfo::try_from(info)
+        .map(Some)
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))
+}
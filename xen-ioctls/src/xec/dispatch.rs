@@ -0,0 +1,428 @@
+/*
+ * Copyright 2021-22 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    os::unix::io::AsRawFd,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+};
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::xec::xec::*;
+
+/* What happens when a port is raised */
+pub enum XenEventChannelTarget {
+    /* Called with the port, which is unmasked once it returns */
+    Callback(Box<dyn FnMut(u32) + Send>),
+    /* Written to, the port is unmasked right away */
+    EventFd(EventFd),
+}
+
+type SharedTarget = Arc<Mutex<XenEventChannelTarget>>;
+
+/* Called with the errors the dispatcher thread runs into */
+type ErrorCallback = Box<dyn FnMut(&std::io::Error) + Send>;
+
+/* Targets are cloned out of the map, so they run without it locked */
+#[derive(Default)]
+struct Targets(Mutex<HashMap<u32, SharedTarget>>);
+
+struct DispatcherInner {
+    xec: XenEventChannelHandle,
+    targets: Targets,
+    on_error: Mutex<Option<ErrorCallback>>,
+    /* Errors of the thread that nobody was told about */
+    dropped: AtomicUsize,
+}
+
+/*
+ * Read raised ports, hand them to whoever registered for them and unmask
+ * them.  Either call dispatch() when fd() is readable, from an existing poll
+ * loop, or have a thread do it with spawn().  Callbacks are free to register
+ * and unregister ports, their own included.
+ */
+pub struct XenEventChannelDispatcher {
+    inner: Arc<DispatcherInner>,
+    thread: Option<(JoinHandle<Result<(), std::io::Error>>, EventFd)>,
+}
+
+impl Targets {
+    /* A callback that panicked doesn't make the lock unusable */
+    fn lock(&self) -> MutexGuard<'_, HashMap<u32, SharedTarget>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn register(&self, port: u32, target: XenEventChannelTarget) -> Result<(), std::io::Error> {
+        let mut targets = self.lock();
+
+        if targets.contains_key(&port) {
+            return Err(Error::from(ErrorKind::AlreadyExists));
+        }
+        targets.insert(port, Arc::new(Mutex::new(target)));
+
+        Ok(())
+    }
+
+    fn unregister(&self, port: u32) -> bool {
+        self.lock().remove(&port).is_some()
+    }
+
+    /* Every port has to be unmasked, whatever happens, the first error is returned */
+    fn route<U>(&self, ports: &[u32], mut unmask: U) -> Result<(), std::io::Error>
+    where
+        U: FnMut(u32) -> Result<(), std::io::Error>,
+    {
+        let mut error = None;
+
+        for port in ports.iter() {
+            let target = self.lock().get(port).cloned();
+            let result = match target {
+                Some(target) => Self::fire(&target, *port),
+                /* Unregistered meanwhile, don't leave it masked */
+                None => Ok(()),
+            };
+
+            let result = result.and(unmask(*port));
+            if let (Err(e), None) = (result, &error) {
+                error = Some(e);
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn fire(target: &SharedTarget, port: u32) -> Result<(), std::io::Error> {
+        let mut target = target.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match &mut *target {
+            XenEventChannelTarget::Callback(callback) => {
+                panic::catch_unwind(AssertUnwindSafe(|| callback(port)))
+                    .map_err(|_| Error::other(format!("callback of port {} panicked", port)))
+            }
+            XenEventChannelTarget::EventFd(eventfd) => eventfd.write(1),
+        }
+    }
+}
+
+impl DispatcherInner {
+    fn dispatch(&self) -> Result<usize, std::io::Error> {
+        let mut ports = [0; 64];
+        let mut handled = 0;
+
+        loop {
+            let count = match self.xec.pending_batch(&mut ports) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+
+            self.targets
+                .route(&ports[..count], |port| self.xec.unmask(port))?;
+
+            handled += count;
+        }
+
+        Ok(handled)
+    }
+
+    fn report(&self, e: &std::io::Error) {
+        let mut on_error = self.on_error.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match on_error.as_mut() {
+            Some(callback) => callback(e),
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn run(&self, stop: &EventFd) -> Result<(), std::io::Error> {
+        let mut poll_fds = [
+            libc::pollfd {
+                fd: self.xec.fd()?,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        loop {
+            // SAFETY: `poll_fds` is an array of two valid pollfd structures
+            if unsafe { libc::poll(poll_fds.as_mut_ptr(), 2, -1) } < 0 {
+                let e = Error::last_os_error();
+                match e.kind() {
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+
+            if poll_fds[1].revents != 0 {
+                return Ok(());
+            }
+            /* One bad port or callback doesn't stop the others from being served */
+            if poll_fds[0].revents != 0 {
+                if let Err(e) = self.dispatch() {
+                    self.report(&e);
+                }
+            }
+        }
+    }
+}
+
+impl XenEventChannelDispatcher {
+    pub fn new(xec: XenEventChannelHandle) -> Result<Self, std::io::Error> {
+        xec.set_nonblocking(true)?;
+
+        Ok(XenEventChannelDispatcher {
+            inner: Arc::new(DispatcherInner {
+                xec,
+                targets: Targets::default(),
+                on_error: Mutex::new(None),
+                dropped: AtomicUsize::new(0),
+            }),
+            thread: None,
+        })
+    }
+
+    /* To bind ports, i.e with BoundPort, and notify them */
+    pub fn handle(&self) -> &XenEventChannelHandle {
+        &self.inner.xec
+    }
+
+    pub fn register(&self, port: u32, target: XenEventChannelTarget) -> Result<(), std::io::Error> {
+        self.inner.targets.register(port, target)
+    }
+
+    pub fn register_callback<F>(&self, port: u32, callback: F) -> Result<(), std::io::Error>
+    where
+        F: FnMut(u32) + Send + 'static,
+    {
+        self.register(port, XenEventChannelTarget::Callback(Box::new(callback)))
+    }
+
+    /* `eventfd` is usually a try_clone() of the one the caller waits on */
+    pub fn register_eventfd(&self, port: u32, eventfd: EventFd) -> Result<(), std::io::Error> {
+        self.register(port, XenEventChannelTarget::EventFd(eventfd))
+    }
+
+    /* Returns false if nothing was registered for `port` */
+    pub fn unregister(&self, port: u32) -> bool {
+        self.inner.targets.unregister(port)
+    }
+
+    /*
+     * Errors hit by the thread started with spawn(), which keeps running, are
+     * passed to `callback`.  Without one they're only counted, see
+     * dropped_errors().
+     */
+    pub fn on_error<F>(&self, callback: F)
+    where
+        F: FnMut(&std::io::Error) + Send + 'static,
+    {
+        *self
+            .inner
+            .on_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(callback));
+    }
+
+    /* Errors of the thread that happened while no on_error() callback was set */
+    pub fn dropped_errors(&self) -> usize {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /* Readable whenever dispatch() has something to do */
+    pub fn fd(&self) -> Result<i32, std::io::Error> {
+        self.inner.xec.fd()
+    }
+
+    /* Handle all the ports raised so far without blocking, returns how many */
+    pub fn dispatch(&self) -> Result<usize, std::io::Error> {
+        self.inner.dispatch()
+    }
+
+    /* Run dispatch() from a thread of our own until stop() */
+    pub fn spawn(&mut self) -> Result<(), std::io::Error> {
+        if self.thread.is_some() {
+            return Err(Error::from(ErrorKind::AlreadyExists));
+        }
+
+        let stop = EventFd::new(0)?;
+        let thread_stop = stop.try_clone()?;
+        let inner = Arc::clone(&self.inner);
+        let handle = thread::Builder::new()
+            .name(String::from("evtchn-dispatch"))
+            .spawn(move || inner.run(&thread_stop))?;
+
+        self.thread = Some((handle, stop));
+
+        Ok(())
+    }
+
+    /* Returns the error that made the thread exit, i.e poll() failing */
+    pub fn stop(&mut self) -> Result<(), std::io::Error> {
+        let (handle, stop) = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        stop.write(1)?;
+        handle
+            .join()
+            .map_err(|_| Error::other("evtchn dispatcher thread panicked"))?
+    }
+}
+
+impl Drop for XenEventChannelDispatcher {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn callback<F>(callback: F) -> XenEventChannelTarget
+    where
+        F: FnMut(u32) + Send + 'static,
+    {
+        XenEventChannelTarget::Callback(Box::new(callback))
+    }
+
+    /* Routes `ports` and returns the ports that were unmasked */
+    fn route(targets: &Targets, ports: &[u32]) -> (Result<(), std::io::Error>, Vec<u32>) {
+        let mut unmasked = Vec::new();
+        let result = targets.route(ports, |port| {
+            unmasked.push(port);
+            Ok(())
+        });
+
+        (result, unmasked)
+    }
+
+    #[test]
+    fn register_unregister() {
+        let targets = Targets::default();
+
+        targets.register(1, callback(|_| ())).unwrap();
+        assert_eq!(
+            targets.register(1, callback(|_| ())).unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        targets.register(2, callback(|_| ())).unwrap();
+
+        assert!(targets.unregister(1));
+        assert!(!targets.unregister(1));
+        assert!(!targets.unregister(3));
+        targets.register(1, callback(|_| ())).unwrap();
+    }
+
+    #[test]
+    fn routing() {
+        let targets = Targets::default();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        let callback_fired = Arc::clone(&fired);
+        targets
+            .register(
+                1,
+                callback(move |port| callback_fired.lock().unwrap().push(port)),
+            )
+            .unwrap();
+        targets
+            .register(
+                2,
+                XenEventChannelTarget::EventFd(eventfd.try_clone().unwrap()),
+            )
+            .unwrap();
+
+        /* Port 3 has nothing registered, it's unmasked all the same */
+        let (result, unmasked) = route(&targets, &[1, 3, 2, 1]);
+        result.unwrap();
+        assert_eq!(unmasked, [1, 3, 2, 1]);
+        assert_eq!(*fired.lock().unwrap(), [1, 1]);
+        assert_eq!(eventfd.read().unwrap(), 1);
+
+        targets.unregister(1);
+        let (result, unmasked) = route(&targets, &[1]);
+        result.unwrap();
+        assert_eq!(unmasked, [1]);
+        assert_eq!(fired.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn callback_errors() {
+        let targets = Targets::default();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        targets.register(1, callback(|_| panic!("broken"))).unwrap();
+        let callback_fired = Arc::clone(&fired);
+        targets
+            .register(
+                2,
+                callback(move |port| callback_fired.lock().unwrap().push(port)),
+            )
+            .unwrap();
+
+        /* The panic is returned, the other ports are still served */
+        let (result, unmasked) = route(&targets, &[1, 2]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Other);
+        assert_eq!(unmasked, [1, 2]);
+        assert_eq!(*fired.lock().unwrap(), [2]);
+
+        /* As are unmask errors, after all the ports were tried */
+        let mut unmasked = Vec::new();
+        let result = targets.route(&[2, 3], |port| {
+            unmasked.push(port);
+            Err(Error::from(ErrorKind::InvalidInput))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(unmasked, [2, 3]);
+    }
+
+    #[test]
+    fn callbacks_change_targets() {
+        let targets = Arc::new(Targets::default());
+
+        /* Port 1 hands over to port 2 and goes away */
+        let callback_targets = Arc::clone(&targets);
+        targets
+            .register(
+                1,
+                callback(move |port| {
+                    assert!(callback_targets.unregister(port));
+                    callback_targets.register(2, callback(|_| ())).unwrap();
+                }),
+            )
+            .unwrap();
+
+        let (result, _) = route(&targets, &[1]);
+        result.unwrap();
+        assert!(!targets.unregister(1));
+        assert!(targets.unregister(2));
+    }
+}
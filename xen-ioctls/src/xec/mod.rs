@@ -8,8 +8,18 @@
  * except according to those terms.
  */
 
+mod dispatch;
+mod port;
 pub(crate) mod types;
 mod xec;
+#[cfg(feature = "async")]
+mod xec_async;
+#[cfg(feature = "mio")]
+mod xec_mio;
 
+pub use dispatch::*;
+pub use port::*;
 pub use types::*;
 pub use xec::*;
+#[cfg(feature = "async")]
+pub use xec_async::*;
@@ -0,0 +1,97 @@
+/*
+ * Copyright 2021-22 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::mem::ManuallyDrop;
+
+use crate::xec::xec::*;
+
+/*
+ * A port bound through `xec`, unbound when dropped.  Ports belong to the
+ * handle they were bound with, into_raw() and from_raw() move one in and out
+ * of a BoundPort without unbinding it.
+ */
+#[derive(Debug)]
+pub struct BoundPort<'a> {
+    xec: &'a XenEventChannelHandle,
+    port: u32,
+}
+
+impl<'a> BoundPort<'a> {
+    pub fn interdomain(
+        xec: &'a XenEventChannelHandle,
+        domid: u32,
+        remote_port: u32,
+    ) -> Result<Self, std::io::Error> {
+        let port = xec.bind_interdomain(domid, remote_port)?;
+
+        Ok(BoundPort { xec, port })
+    }
+
+    pub fn unbound(
+        xec: &'a XenEventChannelHandle,
+        remote_domain: u32,
+    ) -> Result<Self, std::io::Error> {
+        let port = xec.bind_unbound_port(remote_domain)?;
+
+        Ok(BoundPort { xec, port })
+    }
+
+    pub fn virq(xec: &'a XenEventChannelHandle, virq: u32) -> Result<Self, std::io::Error> {
+        let port = xec.bind_virq(virq)?;
+
+        Ok(BoundPort { xec, port })
+    }
+
+    pub fn static_port(xec: &'a XenEventChannelHandle, port: u32) -> Result<Self, std::io::Error> {
+        xec.bind_static(port)?;
+
+        Ok(BoundPort { xec, port })
+    }
+
+    /* Take ownership of `port`, which has to be bound through `xec` */
+    pub fn from_raw(xec: &'a XenEventChannelHandle, port: u32) -> Self {
+        BoundPort { xec, port }
+    }
+
+    /* Give up ownership of the port, it stays bound until unbound by hand */
+    pub fn into_raw(self) -> u32 {
+        ManuallyDrop::new(self).port
+    }
+
+    /* The local port, as returned by XenEventChannelHandle::pending() */
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    pub fn handle(&self) -> &'a XenEventChannelHandle {
+        self.xec
+    }
+
+    pub fn notify(&self) -> Result<(), std::io::Error> {
+        self.xec.notify(self.port).map(|_| ())
+    }
+
+    pub fn unmask(&self) -> Result<(), std::io::Error> {
+        self.xec.unmask(self.port)
+    }
+
+    /* As dropping the port, but errors are reported */
+    pub fn unbind(self) -> Result<(), std::io::Error> {
+        let xec = self.xec;
+
+        xec.unbind(self.into_raw()).map(|_| ())
+    }
+}
+
+impl Drop for BoundPort<'_> {
+    fn drop(&mut self) {
+        let _ = self.xec.unbind(self.port);
+    }
+}
@@ -15,6 +15,26 @@ use vmm_sys_util::ioctl::_IOC_NONE;
 
 pub const XEN_EVTCHN_TYPE: u32 = 'E' as u32;
 
+/*
+ * #define IOCTL_EVTCHN_BIND_VIRQ \
+ *      _IOC(_IOC_NONE, 'E', 0, sizeof(struct ioctl_evtchn_bind_virq))
+ */
+ioctl_ioc_nr!(
+    IOCTL_EVTCHN_BIND_VIRQ,
+    _IOC_NONE,
+    XEN_EVTCHN_TYPE,
+    0_u32,
+    std::mem::size_of::<XenIoctlEvtchnBindVirq>() as u32
+);
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+// tools/include/xen-sys/Linux/evtchn.h::struct ioctl_evtchn_bind_virq
+// sizeof(struct ioctl_evtchn_bind_virq) == 4
+pub struct XenIoctlEvtchnBindVirq {
+    pub virq: u32,
+}
+
 /*
  * #define IOCTL_EVTCHN_BIND_INTERDOMAIN \
  *      _IOC(_IOC_NONE, 'E', 1, sizeof(ioctl_evtchn_bind_interdomain))
@@ -36,6 +56,26 @@ pub struct XenIoctlEvtchnBindInterdomain {
     pub remote_port: u32,
 }
 
+/*
+ * #define IOCTL_EVTCHN_BIND_UNBOUND_PORT \
+ *      _IOC(_IOC_NONE, 'E', 2, sizeof(struct ioctl_evtchn_bind_unbound_port))
+ */
+ioctl_ioc_nr!(
+    IOCTL_EVTCHN_BIND_UNBOUND_PORT,
+    _IOC_NONE,
+    XEN_EVTCHN_TYPE,
+    2_u32,
+    std::mem::size_of::<XenIoctlEvtchnBindUnboundPort>() as u32
+);
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+// tools/include/xen-sys/Linux/evtchn.h::struct ioctl_evtchn_bind_unbound_port
+// sizeof(struct ioctl_evtchn_bind_unbound_port) == 4
+pub struct XenIoctlEvtchnBindUnboundPort {
+    pub remote_domain: u32,
+}
+
 /*
  * #define IOCTL_EVTCHN_UNBIND \
  *      _IOC(_IOC_NONE, 'E', 3, sizeof(struct ioctl_evtchn_unbind))
@@ -75,3 +115,49 @@ ioctl_ioc_nr!(
 pub struct XenIoctlEvtchnNotify {
     pub port: u32,
 }
+
+/*
+ * #define IOCTL_EVTCHN_RESET \
+ *      _IOC(_IOC_NONE, 'E', 5, 0)
+ */
+ioctl_ioc_nr!(IOCTL_EVTCHN_RESET, _IOC_NONE, XEN_EVTCHN_TYPE, 5_u32, 0_u32);
+
+/*
+ * #define IOCTL_EVTCHN_RESTRICT_DOMID \
+ *      _IOC(_IOC_NONE, 'E', 6, sizeof(struct ioctl_evtchn_restrict_domid))
+ */
+ioctl_ioc_nr!(
+    IOCTL_EVTCHN_RESTRICT_DOMID,
+    _IOC_NONE,
+    XEN_EVTCHN_TYPE,
+    6_u32,
+    std::mem::size_of::<XenIoctlEvtchnRestrictDomid>() as u32
+);
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+// tools/include/xen-sys/Linux/evtchn.h::struct ioctl_evtchn_restrict_domid
+// sizeof(struct ioctl_evtchn_restrict_domid) == 2
+pub struct XenIoctlEvtchnRestrictDomid {
+    pub domid: u16,
+}
+
+/*
+ * #define IOCTL_EVTCHN_BIND_STATIC \
+ *      _IOC(_IOC_NONE, 'E', 7, sizeof(struct ioctl_evtchn_bind))
+ */
+ioctl_ioc_nr!(
+    IOCTL_EVTCHN_BIND_STATIC,
+    _IOC_NONE,
+    XEN_EVTCHN_TYPE,
+    7_u32,
+    std::mem::size_of::<XenIoctlEvtchnBind>() as u32
+);
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+// tools/include/xen-sys/Linux/evtchn.h::struct ioctl_evtchn_bind
+// sizeof(struct ioctl_evtchn_bind) == 4
+pub struct XenIoctlEvtchnBind {
+    pub port: u32,
+}
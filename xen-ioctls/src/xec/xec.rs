@@ -11,12 +11,13 @@
 use std::{
     convert::TryInto,
     fs::{File, OpenOptions},
-    io::{Error, Read, Write},
-    os::unix::io::AsRawFd,
+    io::{Error, ErrorKind, Read, Write},
+    os::unix::io::{AsRawFd, RawFd},
 };
 
 use crate::{private::*, xec::types::*};
 
+#[derive(Debug)]
 pub struct XenEventChannelHandle {
     fd: File,
 }
@@ -31,6 +32,25 @@ impl XenEventChannelHandle {
         Ok(XenEventChannelHandle { fd })
     }
 
+    /* Bind to a virtual IRQ such as VIRQ_DOM_EXC, returns the local port */
+    pub fn bind_virq(&self, virq: u32) -> Result<u32, std::io::Error> {
+        let mut bind = XenIoctlEvtchnBindVirq { virq };
+
+        // SAFETY: self.fd is a valid HYPERCALL_EVTCHN descriptor, and we pass a
+        // XenIoctlEvtchnBindVirq to the IOCTL_EVTCHN_BIND_VIRQ ioctl
+        match unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                #[allow(clippy::useless_conversion)]
+                IOCTL_EVTCHN_BIND_VIRQ().try_into().unwrap(),
+                std::ptr::addr_of_mut!(bind),
+            )
+        } {
+            ret if ret < 0 => Err(Error::last_os_error()),
+            ret => Ok(ret as u32),
+        }
+    }
+
     pub fn bind_interdomain(&self, domid: u32, remote_port: u32) -> Result<u32, std::io::Error> {
         let mut bind = XenIoctlEvtchnBindInterdomain {
             remote_domain: domid,
@@ -52,6 +72,25 @@ impl XenEventChannelHandle {
         }
     }
 
+    /* Allocate a port `remote_domain` can bind to, returns it */
+    pub fn bind_unbound_port(&self, remote_domain: u32) -> Result<u32, std::io::Error> {
+        let mut bind = XenIoctlEvtchnBindUnboundPort { remote_domain };
+
+        // SAFETY: self.fd is a valid HYPERCALL_EVTCHN descriptor, and we pass a
+        // XenIoctlEvtchnBindUnboundPort to the IOCTL_EVTCHN_BIND_UNBOUND_PORT ioctl
+        match unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                #[allow(clippy::useless_conversion)]
+                IOCTL_EVTCHN_BIND_UNBOUND_PORT().try_into().unwrap(),
+                std::ptr::addr_of_mut!(bind),
+            )
+        } {
+            ret if ret < 0 => Err(Error::last_os_error()),
+            ret => Ok(ret as u32),
+        }
+    }
+
     pub fn unbind(&self, port: u32) -> Result<u32, std::io::Error> {
         let mut unbind = XenIoctlEvtchnUnbind { port };
 
@@ -70,6 +109,60 @@ impl XenEventChannelHandle {
         }
     }
 
+    /* Drop the ports queued for pending() and clear an overflow, ports stay bound */
+    pub fn reset(&self) -> Result<(), std::io::Error> {
+        // SAFETY: self.fd is a valid HYPERCALL_EVTCHN descriptor and the
+        // IOCTL_EVTCHN_RESET ioctl doesn't take an argument
+        match unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                #[allow(clippy::useless_conversion)]
+                IOCTL_EVTCHN_RESET().try_into().unwrap(),
+            )
+        } {
+            ret if ret < 0 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /* From now on only ports of `domid` can be bound through this handle */
+    pub fn restrict_domid(&self, domid: u16) -> Result<(), std::io::Error> {
+        let mut restrict = XenIoctlEvtchnRestrictDomid { domid };
+
+        // SAFETY: self.fd is a valid HYPERCALL_EVTCHN descriptor, and we pass a
+        // XenIoctlEvtchnRestrictDomid to the IOCTL_EVTCHN_RESTRICT_DOMID ioctl
+        match unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                #[allow(clippy::useless_conversion)]
+                IOCTL_EVTCHN_RESTRICT_DOMID().try_into().unwrap(),
+                std::ptr::addr_of_mut!(restrict),
+            )
+        } {
+            ret if ret < 0 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /* Bind a port set up by Xen at boot, i.e on dom0less systems */
+    pub fn bind_static(&self, port: u32) -> Result<(), std::io::Error> {
+        let mut bind = XenIoctlEvtchnBind { port };
+
+        // SAFETY: self.fd is a valid HYPERCALL_EVTCHN descriptor, and we pass a
+        // XenIoctlEvtchnBind to the IOCTL_EVTCHN_BIND_STATIC ioctl
+        match unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                #[allow(clippy::useless_conversion)]
+                IOCTL_EVTCHN_BIND_STATIC().try_into().unwrap(),
+                std::ptr::addr_of_mut!(bind),
+            )
+        } {
+            ret if ret < 0 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
     pub fn fd(&self) -> Result<i32, std::io::Error> {
         Ok(self.fd.as_raw_fd())
     }
@@ -92,15 +185,71 @@ impl XenEventChannelHandle {
         }
     }
 
-    pub fn pending(&mut self) -> Result<u32, std::io::Error> {
+    /* Waits for one of the ports to be raised, and returns it masked */
+    pub fn pending(&self) -> Result<u32, std::io::Error> {
         let mut buffer = [0; 4];
 
-        self.fd
+        (&self.fd)
             .read_exact(&mut buffer[..])
             .map(|_| u32::from_ne_bytes(buffer))
     }
 
-    pub fn unmask(&mut self, port: u32) -> Result<(), std::io::Error> {
-        self.fd.write_all(&port.to_ne_bytes())
+    /*
+     * Fill `ports` with as many raised ports as the device has queued, in one
+     * read, and return how many there are.  Blocks if there are none, unless
+     * the handle is non-blocking.
+     */
+    pub fn pending_batch(&self, ports: &mut [u32]) -> Result<usize, std::io::Error> {
+        // SAFETY: any byte pattern is a valid u32 and the byte slice covers
+        // exactly the memory of `ports`
+        let buffer = unsafe {
+            std::slice::from_raw_parts_mut(
+                ports.as_mut_ptr().cast::<u8>(),
+                std::mem::size_of_val(ports),
+            )
+        };
+
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match (&self.fd).read(buffer) {
+                /* The device only ever hands out whole ports */
+                Ok(len) => return Ok(len / std::mem::size_of::<u32>()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /* Make pending() and pending_batch() fail with WouldBlock instead of sleeping */
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        // SAFETY: self.fd is a valid descriptor, F_GETFL doesn't take an argument
+        let flags = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let flags = match nonblocking {
+            true => flags | libc::O_NONBLOCK,
+            false => flags & !libc::O_NONBLOCK,
+        };
+
+        // SAFETY: self.fd is a valid descriptor and `flags` are its own
+        match unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_SETFL, flags) } {
+            ret if ret < 0 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn unmask(&self, port: u32) -> Result<(), std::io::Error> {
+        (&self.fd).write_all(&port.to_ne_bytes())
+    }
+}
+
+impl AsRawFd for XenEventChannelHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
     }
 }
@@ -0,0 +1,57 @@
+/*
+ * Copyright 2021-22 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use tokio::io::{unix::AsyncFd, Interest};
+
+use crate::xec::xec::*;
+
+/* XenEventChannelHandle for tokio, it has to be created within a runtime */
+pub struct AsyncXenEventChannelHandle {
+    inner: AsyncFd<XenEventChannelHandle>,
+}
+
+impl AsyncXenEventChannelHandle {
+    pub fn new() -> Result<Self, std::io::Error> {
+        Self::from_handle(XenEventChannelHandle::new()?)
+    }
+
+    pub fn from_handle(xec: XenEventChannelHandle) -> Result<Self, std::io::Error> {
+        xec.set_nonblocking(true)?;
+
+        // SAFETY: `xec` owns its descriptor, which is only closed when the
+        // handle is dropped, and as_raw_fd() always returns it
+        let inner = unsafe { AsyncFd::register_with_interest(xec, Interest::READABLE)? };
+
+        Ok(AsyncXenEventChannelHandle { inner })
+    }
+
+    /* To bind, notify and unmask ports, which never blocks */
+    pub fn handle(&self) -> &XenEventChannelHandle {
+        self.inner.get_ref()
+    }
+
+    /* Wait for one of the ports to be raised, and return it masked */
+    pub async fn next_pending(&self) -> Result<u32, std::io::Error> {
+        self.inner
+            .async_io(Interest::READABLE, |xec| xec.pending())
+            .await
+    }
+
+    /* As next_pending(), but returns all the ports raised so far */
+    pub async fn next_pending_batch(&self, ports: &mut [u32]) -> Result<usize, std::io::Error> {
+        self.inner
+            .async_io(Interest::READABLE, |xec| xec.pending_batch(ports))
+            .await
+    }
+
+    pub fn into_inner(self) -> XenEventChannelHandle {
+        self.inner.into_inner()
+    }
+}
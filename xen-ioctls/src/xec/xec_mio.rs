@@ -0,0 +1,43 @@
+/*
+ * Copyright 2021-22 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::os::unix::io::AsRawFd;
+
+use mio::{event::Source, unix::SourceFd, Interest, Registry, Token};
+
+use crate::xec::xec::*;
+
+/*
+ * Readable when ports are raised.  The handle should be non-blocking, see
+ * set_nonblocking(), and drained with pending_batch() until WouldBlock.
+ */
+impl Source for XenEventChannelHandle {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> Result<(), std::io::Error> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> Result<(), std::io::Error> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<(), std::io::Error> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
@@ -0,0 +1,382 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{collections::BTreeMap, io::ErrorKind};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+
+use crate::{path::*, xenbus::*, xs::*};
+
+/* One device instance, i.e the backend of a frontend's vif/0 */
+pub trait XenbusBackendDevice {
+    /* The frontend is ready, the device can start serving it */
+    fn connect(&mut self, driver: &XenbusDriver<'_>) -> Result<(), std::io::Error>;
+    /* The frontend is going away or already gone */
+    fn disconnect(&mut self, driver: &XenbusDriver<'_>) -> Result<(), std::io::Error>;
+    /* Last call before the instance is dropped and its nodes removed */
+    fn destroy(&mut self, _driver: &XenbusDriver<'_>) {}
+}
+
+pub trait XenbusBackendFactory {
+    type Device: XenbusBackendDevice;
+
+    /* Called for each frontend that shows up under backend/<type> */
+    fn create(&mut self, driver: &XenbusDriver<'_>) -> Result<Self::Device, std::io::Error>;
+}
+
+struct XenbusBackendInstance<'a, D> {
+    driver: XenbusDriver<'a>,
+    device: D,
+    connected: bool,
+}
+
+/*
+ * Serve all the devices of one type.  backend/<type> is watched for devices
+ * being added or removed by the toolstack, and the frontend of each device for
+ * its state.  process_events() takes care of the handshake and calls into the
+ * factory and devices.
+ */
+pub struct XenbusBackend<'a, F: XenbusBackendFactory> {
+    xsh: &'a XenStoreHandle,
+    device_type: String,
+    backend_domid: u32,
+    /* backend/<type> in our home */
+    path: XsPath,
+    factory: F,
+    backend_watch: Watch,
+    release_watch: Watch,
+    instances: BTreeMap<(u32, u32), XenbusBackendInstance<'a, F::Device>>,
+    /* Devices that couldn't be set up, until take_errors() */
+    errors: Vec<((u32, u32), std::io::Error)>,
+}
+
+impl<'a, F: XenbusBackendFactory> XenbusBackend<'a, F> {
+    pub fn new(
+        xsh: &'a XenStoreHandle,
+        device_type: &str,
+        factory: F,
+    ) -> Result<Self, std::io::Error> {
+        /* Only there if we're not dom0 */
+        let backend_domid = match xsh.read_str("domid") {
+            Ok(domid) => domid
+                .trim()
+                .parse()
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let path = xsh.domain_backends(backend_domid)?.join(device_type)?;
+        let backend_watch = xsh.create_watch(&path, &format!("xenbus-backend:{}", path))?;
+        let release_watch = xsh.create_watch(
+            XS_RELEASE_DOMAIN,
+            &format!("xenbus-backend:@releaseDomain:{}", path),
+        )?;
+
+        /* The first events are handled by the scan below */
+        backend_watch.read_watch()?;
+        release_watch.read_watch()?;
+
+        let mut backend = XenbusBackend {
+            xsh,
+            device_type: String::from(device_type),
+            backend_domid,
+            path,
+            factory,
+            backend_watch,
+            release_watch,
+            instances: BTreeMap::new(),
+            errors: Vec::new(),
+        };
+
+        backend.scan()?;
+
+        Ok(backend)
+    }
+
+    pub fn device_type(&self) -> &str {
+        &self.device_type
+    }
+
+    pub fn backend_domid(&self) -> u32 {
+        self.backend_domid
+    }
+
+    /* (frontend domid, devid) of the devices being served */
+    pub fn devices(&self) -> Vec<(u32, u32)> {
+        self.instances.keys().copied().collect()
+    }
+
+    /*
+     * (frontend domid, devid) and error of the devices that failed to be set
+     * up since the last call, new() included.  They're tried again whenever
+     * backend/<type> changes.
+     */
+    pub fn take_errors(&mut self) -> Vec<((u32, u32), std::io::Error)> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /* Directory entries that aren't numbers aren't devices, skip them */
+    fn directory_ids(&self, path: &XsPath) -> Result<Vec<u32>, std::io::Error> {
+        match self.xsh.directory(path) {
+            Ok(entries) => Ok(entries.iter().filter_map(|e| e.parse().ok()).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /* Bring the instances in line with what's under backend/<type> */
+    fn scan(&mut self) -> Result<(), std::io::Error> {
+        let mut found = Vec::new();
+
+        for frontend_domid in self.directory_ids(&self.path)? {
+            let frontend_path = self.path.join(&frontend_domid.to_string())?;
+            for devid in self.directory_ids(&frontend_path)? {
+                found.push((frontend_domid, devid));
+            }
+        }
+
+        let gone: Vec<(u32, u32)> = self
+            .instances
+            .keys()
+            .filter(|key| !found.contains(key))
+            .copied()
+            .collect();
+        for key in gone {
+            /* The toolstack already removed the nodes */
+            self.remove(key, false);
+        }
+
+        /* A device that can't be set up doesn't keep the others from being served */
+        for key in found {
+            if self.instances.contains_key(&key) {
+                continue;
+            }
+            if let Err(e) = self.create(key) {
+                self.errors.push((key, e));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create(&mut self, (frontend_domid, devid): (u32, u32)) -> Result<(), std::io::Error> {
+        let device =
+            XenbusDevice::new(&self.device_type, frontend_domid, self.backend_domid, devid);
+        let driver = XenbusDriver::new(self.xsh, device, XenbusRole::Backend)?;
+        let device = self.factory.create(&driver)?;
+
+        driver.set_state(XenbusState::InitWait)?;
+
+        let peer_state = driver.peer_state();
+        self.instances.insert(
+            (frontend_domid, devid),
+            XenbusBackendInstance {
+                driver,
+                device,
+                connected: false,
+            },
+        );
+
+        /* The frontend may be further along already, i.e we were restarted */
+        self.transition((frontend_domid, devid), peer_state)
+    }
+
+    fn remove(&mut self, key: (u32, u32), cleanup: bool) {
+        let mut instance = match self.instances.remove(&key) {
+            Some(instance) => instance,
+            None => return,
+        };
+
+        if instance.connected {
+            let _ = instance.device.disconnect(&instance.driver);
+        }
+        instance.device.destroy(&instance.driver);
+
+        if cleanup {
+            let _ = self.xsh.rm(instance.driver.path());
+
+            /* Don't leave an empty directory behind for the frontend domain */
+            if let Some(frontend_path) = instance.driver.path().parent() {
+                if let Ok(true) = self.xsh.directory(&frontend_path).map(|d| d.is_empty()) {
+                    let _ = self.xsh.rm(&frontend_path);
+                }
+            }
+        }
+    }
+
+    fn frontend_gone(&self, key: (u32, u32)) -> bool {
+        matches!(
+            self.xsh.read_bytes(self.instances[&key].driver.peer_path()),
+            Err(e) if e.kind() == ErrorKind::NotFound
+        )
+    }
+
+    /*
+     * React to the frontend of `key` moving to `state`.  The toolstack may not
+     * have written the frontend yet, so Unknown alone isn't a reason to remove
+     * the device: that's left to @releaseDomain and the backend node going.
+     */
+    fn transition(&mut self, key: (u32, u32), state: XenbusState) -> Result<(), std::io::Error> {
+        if state == XenbusState::Unknown {
+            return Ok(());
+        }
+
+        let instance = self.instances.get_mut(&key).unwrap();
+        let driver = &instance.driver;
+
+        match state {
+            /* Frontend reconnecting after having closed */
+            XenbusState::Initialising
+                if driver.state()? == XenbusState::Closed && driver.online()? =>
+            {
+                driver.set_state(XenbusState::InitWait)?;
+            }
+            XenbusState::Initialised | XenbusState::Connected if !instance.connected => {
+                instance.device.connect(driver)?;
+                instance.connected = true;
+                driver.set_state(XenbusState::Connected)?;
+            }
+            XenbusState::Closing | XenbusState::Closed => {
+                if instance.connected {
+                    instance.connected = false;
+                    instance.device.disconnect(driver)?;
+                }
+
+                if state == XenbusState::Closing {
+                    driver.set_state(XenbusState::Closing)?;
+                } else {
+                    driver.set_state(XenbusState::Closed)?;
+
+                    /* The toolstack wants the device gone */
+                    if !driver.online()? {
+                        self.remove(key, true);
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /* Writes below a device node, i.e our own state, don't add or remove devices */
+    fn inside_instance(&self, path: &str) -> bool {
+        let path = match XsPath::new(path) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+
+        self.instances.values().any(|instance| {
+            let node = instance.driver.path();
+            path != *node && path.starts_with(node)
+        })
+    }
+
+    /* Frontends of domains that were destroyed won't say goodbye */
+    fn reap(&mut self) {
+        let gone: Vec<(u32, u32)> = self
+            .instances
+            .keys()
+            .filter(|key| self.frontend_gone(**key))
+            .copied()
+            .collect();
+
+        for key in gone {
+            self.remove(key, true);
+        }
+    }
+
+    /*
+     * Wait for something to happen, either to backend/<type> or the frontend
+     * of one of the devices, and deal with it.  Errors returned by the device
+     * hooks are passed on, devices the factory fails to create are skipped and
+     * reported by take_errors().
+     */
+    pub fn process_events(&mut self) -> Result<(), std::io::Error> {
+        let keys: Vec<(u32, u32)> = self.instances.keys().copied().collect();
+        let mut poll_fds = vec![
+            PollFd::new(self.backend_watch.fileno()?, PollFlags::POLLIN),
+            PollFd::new(self.release_watch.fileno()?, PollFlags::POLLIN),
+        ];
+        for key in keys.iter() {
+            poll_fds.push(PollFd::new(
+                self.instances[key].driver.fileno()?,
+                PollFlags::POLLIN,
+            ));
+        }
+
+        loop {
+            match poll(&mut poll_fds, -1) {
+                Ok(_) => break,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(std::io::Error::from(e)),
+            }
+        }
+
+        let ready: Vec<bool> = poll_fds
+            .iter()
+            .map(|fd| fd.revents().is_some_and(|r| r.contains(PollFlags::POLLIN)))
+            .collect();
+
+        for (key, ready) in keys.iter().zip(ready[2..].iter()) {
+            if !*ready {
+                continue;
+            }
+            /* Removed while handling a previous event */
+            let instance = match self.instances.get_mut(key) {
+                Some(instance) => instance,
+                None => continue,
+            };
+
+            let old = instance.driver.peer_state();
+            let new = instance.driver.process_event()?;
+            if new != old {
+                self.transition(*key, new)?;
+            }
+        }
+
+        if ready[1] {
+            self.release_watch.read_watch()?;
+            self.reap();
+        }
+
+        if ready[0] {
+            let mut rescan = false;
+            while let Some(event) = self.backend_watch.try_read_watch()? {
+                rescan |= !self.inside_instance(&event.path);
+            }
+            if rescan {
+                self.scan()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), std::io::Error> {
+        loop {
+            self.process_events()?;
+        }
+    }
+}
+
+impl<'a, F: XenbusBackendFactory> Drop for XenbusBackend<'a, F> {
+    fn drop(&mut self) {
+        /* Devices are stopped but the nodes are left for the next backend */
+        let keys: Vec<(u32, u32)> = self.instances.keys().copied().collect();
+
+        for key in keys {
+            self.remove(key, false);
+        }
+    }
+}
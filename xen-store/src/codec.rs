@@ -0,0 +1,1070 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+/*
+ * Encoding and decoding of the xenstore wire protocol, without any I/O.  The
+ * clients and the mock xenstored only move bytes around and use what's here
+ * to make sense of them.
+ */
+
+use std::{
+    convert::TryInto,
+    io::{Error, ErrorKind},
+    mem,
+};
+
+use crate::{perms::*, types::*, xs::*};
+
+// xen/include/public/io/xs_wire.h::struct xsd_sockmsg
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct XsHeader {
+    pub r#type: u32,
+    pub req_id: u32,
+    pub tx_id: u32,
+    pub len: u32,
+}
+
+pub const XS_HEADER_SIZE: usize = mem::size_of::<XsHeader>();
+
+impl XsHeader {
+    /* Fields are in host byte order, both ends run on the same machine */
+    pub fn encode(&self) -> [u8; XS_HEADER_SIZE] {
+        let mut header = [0; XS_HEADER_SIZE];
+
+        for (chunk, field) in
+            header
+                .chunks_exact_mut(4)
+                .zip([self.r#type, self.req_id, self.tx_id, self.len])
+        {
+            chunk.copy_from_slice(&field.to_ne_bytes());
+        }
+
+        header
+    }
+
+    /*
+     * A header with a payload longer than XENSTORE_PAYLOAD_MAX means the
+     * stream can't be trusted anymore.  Unknown types are let through, the
+     * packet can still be skipped and answered with XS_ERROR.
+     */
+    pub fn decode(header: &[u8; XS_HEADER_SIZE]) -> Result<Self, std::io::Error> {
+        let field =
+            |index: usize| u32::from_ne_bytes(header[index * 4..index * 4 + 4].try_into().unwrap());
+        let header = XsHeader {
+            r#type: field(0),
+            req_id: field(1),
+            tx_id: field(2),
+            len: field(3),
+        };
+
+        if header.len > XENSTORE_PAYLOAD_MAX {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("payload of {} bytes is too long", header.len),
+            ));
+        }
+        Ok(header)
+    }
+}
+
+/* A header and its payload */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct XsPacket {
+    pub r#type: u32,
+    pub req_id: u32,
+    pub tx_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl XsPacket {
+    pub fn new(
+        r#type: u32,
+        req_id: u32,
+        tx_id: u32,
+        payload: Vec<u8>,
+    ) -> Result<Self, std::io::Error> {
+        if payload.len() > XENSTORE_PAYLOAD_MAX as usize {
+            return Err(Error::from_raw_os_error(nix::libc::E2BIG));
+        }
+
+        Ok(XsPacket {
+            r#type,
+            req_id,
+            tx_id,
+            payload,
+        })
+    }
+
+    pub fn header(&self) -> XsHeader {
+        XsHeader {
+            r#type: self.r#type,
+            req_id: self.req_id,
+            tx_id: self.tx_id,
+            len: self.payload.len() as u32,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(XS_HEADER_SIZE + self.payload.len());
+
+        bytes.extend_from_slice(&self.header().encode());
+        bytes.extend_from_slice(&self.payload);
+
+        bytes
+    }
+
+    pub fn from_parts(header: XsHeader, payload: Vec<u8>) -> Result<Self, std::io::Error> {
+        if payload.len() != header.len as usize {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+
+        Self::new(header.r#type, header.req_id, header.tx_id, payload)
+    }
+
+    /*
+     * Decode the packet at the start of `bytes`, along with the number of
+     * bytes it's made of.  Ok(None) means more bytes are needed.
+     */
+    pub fn decode(bytes: &[u8]) -> Result<Option<(Self, usize)>, std::io::Error> {
+        let header = match bytes.get(..XS_HEADER_SIZE) {
+            Some(header) => XsHeader::decode(header.try_into().unwrap())?,
+            None => return Ok(None),
+        };
+        let len = XS_HEADER_SIZE + header.len as usize;
+
+        match bytes.get(XS_HEADER_SIZE..len) {
+            Some(payload) => Ok(Some((Self::from_parts(header, payload.to_vec())?, len))),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) fn parse_directory(body: &str) -> Vec<String> {
+    body.split('\0')
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn invalid_data() -> Error {
+    Error::from(ErrorKind::InvalidData)
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str) -> Result<T, std::io::Error> {
+    arg.parse().map_err(|_| invalid_data())
+}
+
+/* The NUL terminated strings a payload is made of */
+fn payload_args(payload: &[u8]) -> Result<Vec<&str>, std::io::Error> {
+    let payload =
+        std::str::from_utf8(payload).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(payload
+        .strip_suffix('\0')
+        .unwrap_or(payload)
+        .split('\0')
+        .collect())
+}
+
+/* A single string, NUL terminated or not */
+fn payload_string(payload: Vec<u8>) -> Result<String, std::io::Error> {
+    body_to_string(payload).map(|s| String::from(s.trim_end_matches('\0')))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XsRequest {
+    Control {
+        args: Vec<String>,
+    },
+    Directory {
+        path: String,
+    },
+    DirectoryPart {
+        path: String,
+        offset: usize,
+    },
+    Read {
+        path: String,
+    },
+    GetPerms {
+        path: String,
+    },
+    Watch {
+        path: String,
+        token: String,
+    },
+    Unwatch {
+        path: String,
+        token: String,
+    },
+    TransactionStart,
+    TransactionEnd {
+        commit: bool,
+    },
+    Introduce {
+        domid: u32,
+        mfn: u64,
+        port: u32,
+    },
+    Release {
+        domid: u32,
+    },
+    GetDomainPath {
+        domid: u32,
+    },
+    Write {
+        path: String,
+        value: Vec<u8>,
+    },
+    Mkdir {
+        path: String,
+    },
+    Rm {
+        path: String,
+    },
+    SetPerms {
+        path: String,
+        perms: Vec<XsPermission>,
+    },
+    IsDomainIntroduced {
+        domid: u32,
+    },
+    Resume {
+        domid: u32,
+    },
+    SetTarget {
+        domid: u32,
+        target: u32,
+    },
+    ResetWatches,
+}
+
+impl XsRequest {
+    pub fn r#type(&self) -> u32 {
+        match self {
+            XsRequest::Control { .. } => XS_CONTROL,
+            XsRequest::Directory { .. } => XS_DIRECTORY,
+            XsRequest::DirectoryPart { .. } => XS_DIRECTORY_PART,
+            XsRequest::Read { .. } => XS_READ,
+            XsRequest::GetPerms { .. } => XS_GET_PERMS,
+            XsRequest::Watch { .. } => XS_WATCH,
+            XsRequest::Unwatch { .. } => XS_UNWATCH,
+            XsRequest::TransactionStart => XS_TRANSACTION_START,
+            XsRequest::TransactionEnd { .. } => XS_TRANSACTION_END,
+            XsRequest::Introduce { .. } => XS_INTRODUCE,
+            XsRequest::Release { .. } => XS_RELEASE,
+            XsRequest::GetDomainPath { .. } => XS_GET_DOMAIN_PATH,
+            XsRequest::Write { .. } => XS_WRITE,
+            XsRequest::Mkdir { .. } => XS_MKDIR,
+            XsRequest::Rm { .. } => XS_RM,
+            XsRequest::SetPerms { .. } => XS_SET_PERMS,
+            XsRequest::IsDomainIntroduced { .. } => XS_IS_DOMAIN_INTRODUCED,
+            XsRequest::Resume { .. } => XS_RESUME,
+            XsRequest::SetTarget { .. } => XS_SET_TARGET,
+            XsRequest::ResetWatches => XS_RESET_WATCHES,
+        }
+    }
+
+    /* Strings can't contain NUL, ErrorKind::InvalidInput if one does */
+    pub fn encode_payload(&self) -> Result<Vec<u8>, std::io::Error> {
+        let strings =
+            |args: &[&str]| xs_payload(&args.iter().map(|arg| arg.as_bytes()).collect::<Vec<_>>());
+
+        match self {
+            XsRequest::Control { args } => {
+                strings(&args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>())
+            }
+            XsRequest::Directory { path }
+            | XsRequest::Read { path }
+            | XsRequest::GetPerms { path }
+            | XsRequest::Mkdir { path }
+            | XsRequest::Rm { path } => strings(&[path]),
+            XsRequest::DirectoryPart { path, offset } => strings(&[path, &offset.to_string()]),
+            XsRequest::Watch { path, token } | XsRequest::Unwatch { path, token } => {
+                strings(&[path, token])
+            }
+            /* libxenstore sends an empty string for requests without arguments */
+            XsRequest::TransactionStart | XsRequest::ResetWatches => strings(&[""]),
+            XsRequest::TransactionEnd { commit } => strings(&[if *commit { "T" } else { "F" }]),
+            XsRequest::Introduce { domid, mfn, port } => {
+                strings(&[&domid.to_string(), &mfn.to_string(), &port.to_string()])
+            }
+            XsRequest::Release { domid }
+            | XsRequest::GetDomainPath { domid }
+            | XsRequest::IsDomainIntroduced { domid }
+            | XsRequest::Resume { domid } => strings(&[&domid.to_string()]),
+            /* Values aren't NUL terminated and may contain anything */
+            XsRequest::Write { path, value } => {
+                let mut payload = strings(&[path])?;
+                payload.extend_from_slice(value);
+                Ok(payload)
+            }
+            XsRequest::SetPerms { path, perms } => {
+                let perms: Vec<String> = perms.iter().map(|perm| perm.to_string()).collect();
+                let mut args = vec![path.as_str()];
+                args.extend(perms.iter().map(|perm| perm.as_str()));
+                strings(&args)
+            }
+            XsRequest::SetTarget { domid, target } => {
+                strings(&[&domid.to_string(), &target.to_string()])
+            }
+        }
+    }
+
+    pub fn to_packet(&self, req_id: u32, tx_id: u32) -> Result<XsPacket, std::io::Error> {
+        XsPacket::new(self.r#type(), req_id, tx_id, self.encode_payload()?)
+    }
+
+    pub fn from_packet(packet: &XsPacket) -> Result<Self, std::io::Error> {
+        /* XS_WRITE is the only request whose payload isn't all strings */
+        if packet.r#type == XS_WRITE {
+            let nul = packet
+                .payload
+                .iter()
+                .position(|c| *c == 0)
+                .ok_or_else(invalid_data)?;
+            let path = std::str::from_utf8(&packet.payload[..nul]).map_err(|_| invalid_data())?;
+
+            return Ok(XsRequest::Write {
+                path: String::from(path),
+                value: packet.payload[nul + 1..].to_vec(),
+            });
+        }
+
+        let args = payload_args(&packet.payload)?;
+        let arg = |index: usize| args.get(index).copied().ok_or_else(invalid_data);
+        let path = || arg(0).map(String::from);
+        let domid = || arg(0).and_then(parse_number);
+
+        match packet.r#type {
+            XS_CONTROL => Ok(XsRequest::Control {
+                args: args.iter().map(|arg| String::from(*arg)).collect(),
+            }),
+            XS_DIRECTORY => Ok(XsRequest::Directory { path: path()? }),
+            XS_DIRECTORY_PART => Ok(XsRequest::DirectoryPart {
+                path: path()?,
+                offset: parse_number(arg(1)?)?,
+            }),
+            XS_READ => Ok(XsRequest::Read { path: path()? }),
+            XS_GET_PERMS => Ok(XsRequest::GetPerms { path: path()? }),
+            XS_WATCH => Ok(XsRequest::Watch {
+                path: path()?,
+                token: String::from(arg(1)?),
+            }),
+            XS_UNWATCH => Ok(XsRequest::Unwatch {
+                path: path()?,
+                token: String::from(arg(1)?),
+            }),
+            XS_TRANSACTION_START => Ok(XsRequest::TransactionStart),
+            XS_TRANSACTION_END => match arg(0)? {
+                "T" => Ok(XsRequest::TransactionEnd { commit: true }),
+                "F" => Ok(XsRequest::TransactionEnd { commit: false }),
+                _ => Err(invalid_data()),
+            },
+            XS_INTRODUCE => Ok(XsRequest::Introduce {
+                domid: domid()?,
+                mfn: parse_number(arg(1)?)?,
+                port: parse_number(arg(2)?)?,
+            }),
+            XS_RELEASE => Ok(XsRequest::Release { domid: domid()? }),
+            XS_GET_DOMAIN_PATH => Ok(XsRequest::GetDomainPath { domid: domid()? }),
+            XS_MKDIR => Ok(XsRequest::Mkdir { path: path()? }),
+            XS_RM => Ok(XsRequest::Rm { path: path()? }),
+            XS_SET_PERMS => Ok(XsRequest::SetPerms {
+                path: path()?,
+                perms: args[1..]
+                    .iter()
+                    .map(|perm| perm.parse())
+                    .collect::<Result<_, _>>()?,
+            }),
+            XS_IS_DOMAIN_INTRODUCED => Ok(XsRequest::IsDomainIntroduced { domid: domid()? }),
+            XS_RESUME => Ok(XsRequest::Resume { domid: domid()? }),
+            XS_SET_TARGET => Ok(XsRequest::SetTarget {
+                domid: domid()?,
+                target: parse_number(arg(1)?)?,
+            }),
+            XS_RESET_WATCHES => Ok(XsRequest::ResetWatches),
+            _ => Err(invalid_data()),
+        }
+    }
+}
+
+/* What xenstored sends back, watch events included */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XsReply {
+    /* "OK", for requests that don't return anything */
+    Ok,
+    Value(Vec<u8>),
+    Directory(Vec<String>),
+    DirectoryPart {
+        generation: String,
+        children: Vec<String>,
+        last: bool,
+    },
+    Perms(Vec<XsPermission>),
+    TransactionStarted(u32),
+    DomainPath(String),
+    IsDomainIntroduced(bool),
+    Control(String),
+    /* XS_ERROR, as an errno */
+    Error(i32),
+    WatchEvent(WatchEvent),
+}
+
+impl XsReply {
+    pub fn encode_payload(&self) -> Result<Vec<u8>, std::io::Error> {
+        let strings = |args: &[String]| {
+            xs_payload(&args.iter().map(|arg| arg.as_bytes()).collect::<Vec<_>>())
+        };
+
+        match self {
+            XsReply::Ok => xs_payload(&[b"OK"]),
+            XsReply::Value(value) => Ok(value.clone()),
+            XsReply::Directory(children) => strings(children),
+            XsReply::DirectoryPart {
+                generation,
+                children,
+                last,
+            } => {
+                let mut payload = xs_payload(&[generation.as_bytes()])?;
+                payload.extend(strings(children)?);
+                /* The last chunk has an extra NUL */
+                if *last {
+                    payload.push(0);
+                }
+                Ok(payload)
+            }
+            XsReply::Perms(perms) => strings(
+                &perms
+                    .iter()
+                    .map(|perm| perm.to_string())
+                    .collect::<Vec<_>>(),
+            ),
+            XsReply::TransactionStarted(tx_id) => xs_payload(&[tx_id.to_string().as_bytes()]),
+            XsReply::DomainPath(path) => xs_payload(&[path.as_bytes()]),
+            XsReply::IsDomainIntroduced(introduced) => {
+                xs_payload(&[if *introduced { b"T" } else { b"F" }])
+            }
+            XsReply::Control(res) => xs_payload(&[res.as_bytes()]),
+            XsReply::Error(errno) => xs_payload(&[xsd_error_name(*errno).as_bytes()]),
+            XsReply::WatchEvent(event) => {
+                xs_payload(&[event.path.as_bytes(), event.token.as_bytes()])
+            }
+        }
+    }
+
+    /* `request_type` is the type of the request being answered */
+    pub fn to_packet(
+        &self,
+        request_type: u32,
+        req_id: u32,
+        tx_id: u32,
+    ) -> Result<XsPacket, std::io::Error> {
+        let r#type = match self {
+            XsReply::Error(_) => XS_ERROR,
+            /* Not a reply to anything */
+            XsReply::WatchEvent(_) => {
+                return XsPacket::new(XS_WATCH_EVENT, 0, 0, self.encode_payload()?)
+            }
+            _ => request_type,
+        };
+
+        XsPacket::new(r#type, req_id, tx_id, self.encode_payload()?)
+    }
+
+    /*
+     * How the payload of a reply reads depends on the request it answers, of
+     * type `request_type`.
+     */
+    pub fn from_packet(request_type: u32, packet: &XsPacket) -> Result<Self, std::io::Error> {
+        let payload = packet.payload.clone();
+
+        match packet.r#type {
+            XS_ERROR => {
+                return Ok(XsReply::Error(xsd_errno(&String::from_utf8_lossy(
+                    &payload,
+                ))))
+            }
+            XS_WATCH_EVENT => {
+                let args = payload_args(&payload)?;
+                return match args[..] {
+                    [path, token, ..] => Ok(XsReply::WatchEvent(WatchEvent {
+                        path: String::from(path),
+                        token: String::from(token),
+                    })),
+                    _ => Err(invalid_data()),
+                };
+            }
+            r#type if r#type != request_type => return Err(invalid_data()),
+            _ => (),
+        }
+
+        match request_type {
+            XS_READ => Ok(XsReply::Value(payload)),
+            XS_DIRECTORY => Ok(XsReply::Directory(parse_directory(&body_to_string(
+                payload,
+            )?))),
+            XS_DIRECTORY_PART => {
+                let payload = body_to_string(payload)?;
+                let (generation, chunk) = payload.split_once('\0').ok_or_else(invalid_data)?;
+                let (children, last) = match chunk.strip_suffix('\0') {
+                    Some(rest) if rest.is_empty() || rest.ends_with('\0') => (rest, true),
+                    _ => (chunk, false),
+                };
+
+                Ok(XsReply::DirectoryPart {
+                    generation: String::from(generation),
+                    children: parse_directory(children),
+                    last,
+                })
+            }
+            XS_GET_PERMS => Ok(XsReply::Perms(
+                parse_directory(&body_to_string(payload)?)
+                    .iter()
+                    .map(|perm| perm.parse())
+                    .collect::<Result<_, _>>()?,
+            )),
+            XS_TRANSACTION_START => Ok(XsReply::TransactionStarted(parse_number(
+                &payload_string(payload)?,
+            )?)),
+            XS_GET_DOMAIN_PATH => Ok(XsReply::DomainPath(payload_string(payload)?)),
+            XS_IS_DOMAIN_INTRODUCED => match payload_string(payload)?.as_str() {
+                "T" => Ok(XsReply::IsDomainIntroduced(true)),
+                "F" => Ok(XsReply::IsDomainIntroduced(false)),
+                _ => Err(invalid_data()),
+            },
+            XS_CONTROL => Ok(XsReply::Control(payload_string(payload)?)),
+            _ => Ok(XsReply::Ok),
+        }
+    }
+
+    /* XsReply::Error as an Err */
+    pub fn into_result(self) -> Result<Self, std::io::Error> {
+        match self {
+            XsReply::Error(errno) => Err(Error::from_raw_os_error(errno)),
+            reply => Ok(reply),
+        }
+    }
+}
+
+/*
+ * The listing of a node with too many children for a single XS_DIRECTORY
+ * reply.  Each XS_DIRECTORY_PART reply is made of the node's generation count
+ * followed by as many children as fit in the payload, starting at byte
+ * `offset` of the complete list.  A change in generation count means the node
+ * was modified while we were reading it, in which case we start over.
+ */
+pub(crate) struct XsDirectoryParts {
+    path: String,
+    generation: Option<String>,
+    children: Vec<String>,
+}
+
+impl XsDirectoryParts {
+    pub(crate) fn new(path: &str) -> Self {
+        XsDirectoryParts {
+            path: String::from(path),
+            generation: None,
+            children: Vec::new(),
+        }
+    }
+
+    /* Asks for what comes after the children received so far */
+    pub(crate) fn request(&self) -> XsRequest {
+        XsRequest::DirectoryPart {
+            path: self.path.clone(),
+            /* Children are NUL terminated in the complete list */
+            offset: self.children.iter().map(|child| child.len() + 1).sum(),
+        }
+    }
+
+    /* The complete list once the last chunk is in */
+    pub(crate) fn push(&mut self, reply: XsReply) -> Result<Option<Vec<String>>, std::io::Error> {
+        let (generation, chunk, last) = match reply {
+            XsReply::DirectoryPart {
+                generation,
+                children,
+                last,
+            } => (generation, children, last),
+            _ => return Err(invalid_data()),
+        };
+
+        if self
+            .generation
+            .as_ref()
+            .is_some_and(|gen| *gen != generation)
+        {
+            self.children.clear();
+            self.generation = Some(generation);
+            return Ok(None);
+        }
+        self.generation = Some(generation);
+
+        self.children.extend(chunk);
+        match last {
+            true => Ok(Some(std::mem::take(&mut self.children))),
+            false => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::libc::{E2BIG, EACCES, EAGAIN, EDQUOT, EEXIST, EINVAL, ENOENT, ENOSPC};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /* Through the bytes a peer would see, and back */
+    fn wire(packet: &XsPacket) -> XsPacket {
+        let bytes = packet.encode();
+        let (decoded, len) = XsPacket::decode(&bytes).unwrap().unwrap();
+
+        assert_eq!(len, bytes.len());
+        decoded
+    }
+
+    fn error_packet(name: &[u8]) -> XsPacket {
+        XsPacket::new(XS_ERROR, 1, 0, name.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let requests = [
+            XsRequest::Control {
+                args: vec![String::from("log"), String::from("on")],
+            },
+            XsRequest::Directory {
+                path: String::from("/local"),
+            },
+            XsRequest::DirectoryPart {
+                path: String::from("/local/domain"),
+                offset: 42,
+            },
+            XsRequest::Read {
+                path: String::from("domid"),
+            },
+            XsRequest::GetPerms {
+                path: String::from("/a"),
+            },
+            XsRequest::Watch {
+                path: String::from("@releaseDomain"),
+                token: String::from("t"),
+            },
+            XsRequest::Unwatch {
+                path: String::from("/a"),
+                token: String::from("t"),
+            },
+            XsRequest::TransactionStart,
+            XsRequest::TransactionEnd { commit: true },
+            XsRequest::TransactionEnd { commit: false },
+            XsRequest::Introduce {
+                domid: 1,
+                mfn: 0x1_0000_0000,
+                port: 3,
+            },
+            XsRequest::Release { domid: 1 },
+            XsRequest::GetDomainPath { domid: 2 },
+            XsRequest::Write {
+                path: String::from("/a"),
+                value: b"with\0nul\0".to_vec(),
+            },
+            XsRequest::Mkdir {
+                path: String::from("/a/b"),
+            },
+            XsRequest::Rm {
+                path: String::from("/a"),
+            },
+            XsRequest::SetPerms {
+                path: String::from("/a"),
+                perms: vec![
+                    XsPermission::new(0, XsAccess::None),
+                    XsPermission::new(1, XsAccess::Read),
+                    XsPermission::new(2, XsAccess::Both),
+                ],
+            },
+            XsRequest::IsDomainIntroduced { domid: 3 },
+            XsRequest::Resume { domid: 3 },
+            XsRequest::SetTarget {
+                domid: 3,
+                target: 4,
+            },
+            XsRequest::ResetWatches,
+        ];
+
+        for request in requests.iter() {
+            let packet = wire(&request.to_packet(7, 9).unwrap());
+
+            assert_eq!(packet.r#type, request.r#type());
+            assert_eq!((packet.req_id, packet.tx_id), (7, 9));
+            assert_eq!(&XsRequest::from_packet(&packet).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let replies = [
+            (XS_WRITE, XsReply::Ok),
+            (XS_READ, XsReply::Value(b"raw\0value".to_vec())),
+            (
+                XS_DIRECTORY,
+                XsReply::Directory(vec![String::from("a"), String::from("b")]),
+            ),
+            (
+                XS_DIRECTORY_PART,
+                XsReply::DirectoryPart {
+                    generation: String::from("12"),
+                    children: vec![String::from("a"), String::from("b")],
+                    last: false,
+                },
+            ),
+            (
+                XS_DIRECTORY_PART,
+                XsReply::DirectoryPart {
+                    generation: String::from("12"),
+                    children: vec![String::from("c")],
+                    last: true,
+                },
+            ),
+            (
+                XS_GET_PERMS,
+                XsReply::Perms(vec![
+                    XsPermission::new(0, XsAccess::Write),
+                    XsPermission::new(5, XsAccess::Read),
+                ]),
+            ),
+            (XS_TRANSACTION_START, XsReply::TransactionStarted(3)),
+            (
+                XS_GET_DOMAIN_PATH,
+                XsReply::DomainPath(String::from("/local/domain/1")),
+            ),
+            (XS_IS_DOMAIN_INTRODUCED, XsReply::IsDomainIntroduced(true)),
+            (XS_IS_DOMAIN_INTRODUCED, XsReply::IsDomainIntroduced(false)),
+            (XS_CONTROL, XsReply::Control(String::from("done"))),
+            (XS_READ, XsReply::Error(ENOENT)),
+            (
+                XS_WATCH,
+                XsReply::WatchEvent(WatchEvent {
+                    path: String::from("/a/b"),
+                    token: String::from("t"),
+                }),
+            ),
+        ];
+
+        for (request_type, reply) in replies.iter() {
+            let packet = wire(&reply.to_packet(*request_type, 7, 9).unwrap());
+
+            assert_eq!(
+                &XsReply::from_packet(*request_type, &packet).unwrap(),
+                reply
+            );
+        }
+    }
+
+    #[test]
+    fn header_too_long() {
+        let header = XsHeader {
+            r#type: XS_READ,
+            req_id: 1,
+            tx_id: 0,
+            len: XENSTORE_PAYLOAD_MAX + 1,
+        };
+
+        let e = XsHeader::decode(&header.encode()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(XsPacket::decode(&header.encode()).is_err());
+
+        let header = XsHeader {
+            len: XENSTORE_PAYLOAD_MAX,
+            ..header
+        };
+        assert_eq!(XsHeader::decode(&header.encode()).unwrap(), header);
+    }
+
+    #[test]
+    fn header_unknown_type() {
+        /* Left to the receiver to answer, the stream is still fine */
+        let header = XsHeader {
+            r#type: 999,
+            req_id: 1,
+            tx_id: 0,
+            len: 0,
+        };
+
+        assert_eq!(XsHeader::decode(&header.encode()).unwrap(), header);
+    }
+
+    #[test]
+    fn packet_truncated() {
+        let request = XsRequest::Read {
+            path: String::from("/local/domain/0/name"),
+        };
+        let bytes = request.to_packet(1, 0).unwrap().encode();
+
+        for len in 0..bytes.len() {
+            assert!(XsPacket::decode(&bytes[..len]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn packet_back_to_back() {
+        let first = XsRequest::TransactionStart.to_packet(1, 0).unwrap();
+        let second = XsRequest::ResetWatches.to_packet(2, 0).unwrap();
+        let mut bytes = first.encode();
+        bytes.extend(second.encode());
+
+        let (packet, len) = XsPacket::decode(&bytes).unwrap().unwrap();
+        assert_eq!(packet, first);
+        let (packet, _) = XsPacket::decode(&bytes[len..]).unwrap().unwrap();
+        assert_eq!(packet, second);
+    }
+
+    #[test]
+    fn directory_part_last() {
+        let part = |payload: &[u8]| {
+            let packet = XsPacket::new(XS_DIRECTORY_PART, 1, 0, payload.to_vec()).unwrap();
+            XsReply::from_packet(XS_DIRECTORY_PART, &packet).unwrap()
+        };
+        let reply = |children: &[&str], last: bool| XsReply::DirectoryPart {
+            generation: String::from("3"),
+            children: children.iter().map(|child| String::from(*child)).collect(),
+            last,
+        };
+
+        assert_eq!(part(b"3\0a\0b\0"), reply(&["a", "b"], false));
+        /* The extra NUL ends the listing */
+        assert_eq!(part(b"3\0a\0b\0\0"), reply(&["a", "b"], true));
+        assert_eq!(part(b"3\0\0"), reply(&[], true));
+
+        let encoded = reply(&["a"], true).encode_payload().unwrap();
+        assert_eq!(encoded, b"3\0a\0\0");
+    }
+
+    #[test]
+    fn directory_parts() {
+        let part = |generation: &str, children: &[&str], last: bool| XsReply::DirectoryPart {
+            generation: String::from(generation),
+            children: children.iter().map(|child| String::from(*child)).collect(),
+            last,
+        };
+        let offset = |parts: &XsDirectoryParts| match parts.request() {
+            XsRequest::DirectoryPart { path, offset } => {
+                assert_eq!(path, "/a");
+                offset
+            }
+            _ => unreachable!(),
+        };
+        let mut parts = XsDirectoryParts::new("/a");
+
+        assert_eq!(offset(&parts), 0);
+        assert_eq!(parts.push(part("1", &["b", "cc"], false)).unwrap(), None);
+        assert_eq!(offset(&parts), 5);
+
+        /* Modified meanwhile, start over */
+        assert_eq!(parts.push(part("2", &["d"], false)).unwrap(), None);
+        assert_eq!(offset(&parts), 0);
+        assert_eq!(parts.push(part("2", &["b"], false)).unwrap(), None);
+        assert_eq!(
+            parts.push(part("2", &["d"], true)).unwrap(),
+            Some(vec![String::from("b"), String::from("d")])
+        );
+
+        assert!(parts.push(XsReply::Ok).is_err());
+    }
+
+    #[test]
+    fn error_names() {
+        let names: [(&[u8], i32); 6] = [
+            (b"ENOENT\0", ENOENT),
+            (b"EACCES\0", EACCES),
+            (b"EAGAIN\0", EAGAIN),
+            (b"E2BIG\0", E2BIG),
+            (b"EQUOTA\0", EDQUOT),
+            /* Not NUL terminated */
+            (b"EACCES", EACCES),
+        ];
+
+        for (name, errno) in names.iter() {
+            let reply = XsReply::from_packet(XS_READ, &error_packet(name)).unwrap();
+
+            assert_eq!(reply, XsReply::Error(*errno));
+            let e = reply.into_result().unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(*errno));
+        }
+
+        for name in [&b"ENOTANERROR\0"[..], b"", b"\xff\xfe"].iter() {
+            let reply = XsReply::from_packet(XS_READ, &error_packet(name)).unwrap();
+            assert_eq!(reply, XsReply::Error(EINVAL));
+        }
+    }
+
+    /* Anything but NUL, which separates the strings of a payload */
+    fn string() -> impl Strategy<Value = String> {
+        "[^\u{0}]{0,24}"
+    }
+
+    /* Directory listings skip empty names */
+    fn children() -> impl Strategy<Value = Vec<String>> {
+        prop::collection::vec("[^\u{0}]{1,24}", 0..8)
+    }
+
+    fn perms() -> impl Strategy<Value = Vec<XsPermission>> {
+        let access = prop_oneof![
+            Just(XsAccess::None),
+            Just(XsAccess::Read),
+            Just(XsAccess::Write),
+            Just(XsAccess::Both),
+        ];
+
+        prop::collection::vec(
+            (any::<u32>(), access).prop_map(|(domid, access)| XsPermission::new(domid, access)),
+            0..8,
+        )
+    }
+
+    fn request() -> impl Strategy<Value = XsRequest> {
+        prop_oneof![
+            prop::collection::vec(string(), 1..4).prop_map(|args| XsRequest::Control { args }),
+            string().prop_map(|path| XsRequest::Directory { path }),
+            (string(), any::<usize>())
+                .prop_map(|(path, offset)| XsRequest::DirectoryPart { path, offset }),
+            string().prop_map(|path| XsRequest::Read { path }),
+            string().prop_map(|path| XsRequest::GetPerms { path }),
+            (string(), string()).prop_map(|(path, token)| XsRequest::Watch { path, token }),
+            (string(), string()).prop_map(|(path, token)| XsRequest::Unwatch { path, token }),
+            Just(XsRequest::TransactionStart),
+            any::<bool>().prop_map(|commit| XsRequest::TransactionEnd { commit }),
+            (any::<u32>(), any::<u64>(), any::<u32>())
+                .prop_map(|(domid, mfn, port)| XsRequest::Introduce { domid, mfn, port }),
+            any::<u32>().prop_map(|domid| XsRequest::Release { domid }),
+            any::<u32>().prop_map(|domid| XsRequest::GetDomainPath { domid }),
+            (string(), prop::collection::vec(any::<u8>(), 0..2048))
+                .prop_map(|(path, value)| XsRequest::Write { path, value }),
+            string().prop_map(|path| XsRequest::Mkdir { path }),
+            string().prop_map(|path| XsRequest::Rm { path }),
+            (string(), perms()).prop_map(|(path, perms)| XsRequest::SetPerms { path, perms }),
+            any::<u32>().prop_map(|domid| XsRequest::IsDomainIntroduced { domid }),
+            any::<u32>().prop_map(|domid| XsRequest::Resume { domid }),
+            (any::<u32>(), any::<u32>())
+                .prop_map(|(domid, target)| XsRequest::SetTarget { domid, target }),
+            Just(XsRequest::ResetWatches),
+        ]
+    }
+
+    /* Along with the type of the request they answer */
+    fn reply() -> impl Strategy<Value = (u32, XsReply)> {
+        let errno = prop::sample::select(vec![EINVAL, EACCES, EEXIST, ENOENT, ENOSPC, EAGAIN]);
+
+        prop_oneof![
+            Just((XS_WRITE, XsReply::Ok)),
+            prop::collection::vec(any::<u8>(), 0..2048)
+                .prop_map(|value| (XS_READ, XsReply::Value(value))),
+            children().prop_map(|children| (XS_DIRECTORY, XsReply::Directory(children))),
+            ("[0-9]{1,8}", children(), any::<bool>()).prop_map(|(generation, children, last)| {
+                let part = XsReply::DirectoryPart {
+                    generation,
+                    children,
+                    last,
+                };
+                (XS_DIRECTORY_PART, part)
+            }),
+            perms().prop_map(|perms| (XS_GET_PERMS, XsReply::Perms(perms))),
+            any::<u32>()
+                .prop_map(|tx_id| (XS_TRANSACTION_START, XsReply::TransactionStarted(tx_id))),
+            string().prop_map(|path| (XS_GET_DOMAIN_PATH, XsReply::DomainPath(path))),
+            any::<bool>().prop_map(|introduced| {
+                let reply = XsReply::IsDomainIntroduced(introduced);
+                (XS_IS_DOMAIN_INTRODUCED, reply)
+            }),
+            string().prop_map(|res| (XS_CONTROL, XsReply::Control(res))),
+            errno.prop_map(|errno| (XS_READ, XsReply::Error(errno))),
+            (string(), string()).prop_map(|(path, token)| {
+                (XS_WATCH, XsReply::WatchEvent(WatchEvent { path, token }))
+            }),
+        ]
+    }
+
+    fn payload() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 0..64)
+    }
+
+    /* A header, with a type that may be known and a length that may be right */
+    fn header() -> impl Strategy<Value = XsHeader> {
+        let len = prop_oneof![0..64u32, 0..=XENSTORE_PAYLOAD_MAX + 1, any::<u32>()];
+
+        (0..32u32, any::<u32>(), any::<u32>(), len).prop_map(|(r#type, req_id, tx_id, len)| {
+            XsHeader {
+                r#type,
+                req_id,
+                tx_id,
+                len,
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn any_request_round_trip(request in request(), req_id: u32, tx_id: u32) {
+            let packet = wire(&request.to_packet(req_id, tx_id).unwrap());
+
+            prop_assert_eq!(packet.r#type, request.r#type());
+            prop_assert_eq!((packet.req_id, packet.tx_id), (req_id, tx_id));
+            prop_assert_eq!(XsRequest::from_packet(&packet).unwrap(), request);
+        }
+
+        #[test]
+        fn any_reply_round_trip((request_type, reply) in reply(), req_id: u32, tx_id: u32) {
+            let packet = wire(&reply.to_packet(request_type, req_id, tx_id).unwrap());
+
+            prop_assert_eq!(XsReply::from_packet(request_type, &packet).unwrap(), reply);
+        }
+
+        /* Whatever comes in, it's decoded or refused but never a panic */
+        #[test]
+        fn garbage_bytes(header in header(), payload in payload()) {
+            let mut bytes = header.encode().to_vec();
+            bytes.extend(payload);
+
+            if let Ok(Some((packet, len))) = XsPacket::decode(&bytes) {
+                prop_assert_eq!(&packet.encode()[..], &bytes[..len]);
+
+                let _ = XsRequest::from_packet(&packet);
+                for request_type in 0..32 {
+                    let _ = XsReply::from_packet(request_type, &packet);
+                }
+            }
+        }
+
+        #[test]
+        fn garbage_header(header in header(), payload in payload()) {
+            let decoded = XsHeader::decode(&header.encode());
+            prop_assert_eq!(decoded.is_ok(), header.len <= XENSTORE_PAYLOAD_MAX);
+
+            /* The payload must be as long as the header says, and not too long */
+            let packet = XsPacket::from_parts(header, payload.clone());
+            prop_assert_eq!(packet.is_ok(), header.len as usize == payload.len());
+
+            let oversized = vec![0; XENSTORE_PAYLOAD_MAX as usize + 1];
+            let header = XsHeader { len: oversized.len() as u32, ..header };
+            prop_assert!(XsPacket::from_parts(header, oversized).is_err());
+        }
+
+        /* Types that aren't requests, or that nobody knows, are refused */
+        #[test]
+        fn unknown_request_type(
+            r#type in prop_oneof![Just(XS_WATCH_EVENT), Just(XS_ERROR), Just(20u32), 23..u32::MAX],
+            payload in payload(),
+        ) {
+            let packet = XsPacket::new(r#type, 1, 0, payload).unwrap();
+
+            prop_assert!(XsRequest::from_packet(&packet).is_err());
+        }
+    }
+}
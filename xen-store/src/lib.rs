@@ -8,7 +8,32 @@
  * except according to those terms.
  */
 
+mod backend;
+mod codec;
+#[cfg(feature = "mock")]
+mod mock;
+mod path;
+mod perms;
+mod server;
+mod subtree;
+mod transport;
 pub(crate) mod types;
+mod xenbus;
 mod xs;
+#[cfg(feature = "async")]
+mod xs_async;
 
+pub use backend::*;
+pub use codec::*;
+#[cfg(feature = "mock")]
+pub use mock::*;
+pub use path::*;
+pub use perms::*;
+pub use server::*;
+pub use subtree::*;
+pub use transport::*;
+pub use types::{XENSTORED_PATH_ENV, XENSTORED_SOCKET};
+pub use xenbus::*;
 pub use xs::*;
+#[cfg(feature = "async")]
+pub use xs_async::*;
@@ -0,0 +1,262 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    thread::JoinHandle,
+};
+
+use crate::{codec::*, perms::*, server::*, transport::*, xs::*};
+
+/* Each instance gets its own directory to put sockets in */
+static NEXT_INSTANCE: AtomicU32 = AtomicU32::new(0);
+
+struct MockState {
+    server: XenStoreServer,
+    streams: HashMap<u64, UnixStream>,
+    /* Requests received while stalled, answered once that's over */
+    stalled: Option<Vec<(u64, XsPacket)>>,
+}
+
+impl MockState {
+    fn flush(&mut self) {
+        for (conn_id, packet) in self.server.take_outgoing() {
+            if let Some(stream) = self.streams.get_mut(&conn_id) {
+                let _ = stream.write_all(&packet.encode());
+            }
+        }
+    }
+}
+
+fn connection_function(mut stream: UnixStream, conn_id: u64, state: Arc<Mutex<MockState>>) {
+    loop {
+        let mut header = [0; XS_HEADER_SIZE];
+
+        if stream.read_exact(&mut header).is_err() {
+            break;
+        }
+        let header = match XsHeader::decode(&header) {
+            Ok(header) => header,
+            Err(_) => break,
+        };
+
+        let mut payload = vec![0; header.len as usize];
+        if stream.read_exact(&mut payload).is_err() {
+            break;
+        }
+        let packet = match XsPacket::from_parts(header, payload) {
+            Ok(packet) => packet,
+            Err(_) => break,
+        };
+
+        let mut state = state.lock().unwrap();
+        if let Some(held) = state.stalled.as_mut() {
+            held.push((conn_id, packet));
+            continue;
+        }
+        state.server.process(conn_id, &packet);
+        state.flush();
+    }
+
+    let mut state = state.lock().unwrap();
+    state.streams.remove(&conn_id);
+    state.server.disconnect(conn_id);
+}
+
+fn listener_function(
+    listener: UnixListener,
+    domid: u32,
+    state: Arc<Mutex<MockState>>,
+    stopped: Arc<AtomicBool>,
+) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        let conn_id = {
+            let mut state = state.lock().unwrap();
+            let tx_stream = match stream.try_clone() {
+                Ok(tx_stream) => tx_stream,
+                Err(_) => continue,
+            };
+
+            let conn_id = state.server.connect(domid);
+            state.streams.insert(conn_id, tx_stream);
+            conn_id
+        };
+
+        let state = Arc::clone(&state);
+        thread::spawn(move || connection_function(stream, conn_id, state));
+    }
+}
+
+/*
+ * An in-memory xenstored listening on unix sockets in a temporary directory,
+ * for testing code that talks to the Xen store on machines without Xen.
+ *
+ * Connections made through path() act as dom0.  Those made through
+ * domain_path() act as the given domain and are subject to permissions.
+ * Everything is torn down when the instance is dropped.
+ */
+pub struct MockXenStored {
+    dir: PathBuf,
+    state: Arc<Mutex<MockState>>,
+    stopped: Arc<AtomicBool>,
+    listeners: Mutex<HashMap<u32, (PathBuf, JoinHandle<()>)>>,
+}
+
+impl MockXenStored {
+    pub fn start() -> Result<Self, std::io::Error> {
+        let dir = std::env::temp_dir().join(format!(
+            "xen-store-mock-{}-{}",
+            process::id(),
+            NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&dir)?;
+
+        let xenstored = MockXenStored {
+            dir,
+            state: Arc::new(Mutex::new(MockState {
+                server: XenStoreServer::new(),
+                streams: HashMap::new(),
+                stalled: None,
+            })),
+            stopped: Arc::new(AtomicBool::new(false)),
+            listeners: Mutex::new(HashMap::new()),
+        };
+
+        xenstored.listen(0)?;
+
+        Ok(xenstored)
+    }
+
+    fn listen(&self, domid: u32) -> Result<PathBuf, std::io::Error> {
+        let mut listeners = self.listeners.lock().unwrap();
+
+        if let Some((path, _)) = listeners.get(&domid) {
+            return Ok(path.clone());
+        }
+
+        let path = self.dir.join(format!("socket-{}", domid));
+        let listener = UnixListener::bind(&path)?;
+        let state = Arc::clone(&self.state);
+        let stopped = Arc::clone(&self.stopped);
+        let handler = thread::spawn(move || listener_function(listener, domid, state, stopped));
+
+        listeners.insert(domid, (path.clone(), handler));
+
+        Ok(path)
+    }
+
+    /* Socket of the privileged, dom0, connections */
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("socket-0")
+    }
+
+    /*
+     * Socket whose connections act as `domid`.  The domain's home,
+     * /local/domain/<domid>, is created and owned by it, as the toolstack
+     * would do.
+     */
+    pub fn domain_path(&self, domid: u32) -> Result<PathBuf, std::io::Error> {
+        if domid != 0 {
+            let path = format!("/local/domain/{}", domid);
+            let mut state = self.state.lock().unwrap();
+
+            if state
+                .server
+                .request(XsRequest::GetPerms { path: path.clone() })
+                .is_err()
+            {
+                state
+                    .server
+                    .request(XsRequest::Mkdir { path: path.clone() })?;
+                state.server.request(XsRequest::SetPerms {
+                    path,
+                    perms: vec![XsPermission::new(domid, XsAccess::None)],
+                })?;
+                state.flush();
+            }
+        }
+
+        self.listen(domid)
+    }
+
+    /* Close the connections of all clients, as a restarting xenstored would */
+    pub fn disconnect_clients(&self) {
+        for stream in self.state.lock().unwrap().streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /*
+     * Stop answering requests, i.e to test timeouts.  Those held meanwhile
+     * are processed, in order, when `stalled` goes back to false.
+     */
+    pub fn set_stalled(&self, stalled: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        match (stalled, state.stalled.take()) {
+            (true, held) => state.stalled = Some(held.unwrap_or_default()),
+            (false, held) => {
+                for (conn_id, packet) in held.unwrap_or_default() {
+                    state.server.process(conn_id, &packet);
+                }
+                state.flush();
+            }
+        }
+    }
+
+    pub fn transport(&self) -> XenStoreTransport {
+        XenStoreTransport::Socket(self.path())
+    }
+
+    pub fn handle(&self) -> Result<XenStoreHandle, std::io::Error> {
+        XenStoreHandle::with_transport(&self.transport())
+    }
+
+    pub fn handle_as(&self, domid: u32) -> Result<XenStoreHandle, std::io::Error> {
+        XenStoreHandle::with_transport(&XenStoreTransport::Socket(self.domain_path(domid)?))
+    }
+}
+
+impl Drop for MockXenStored {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        /* Wake up the listeners so that they notice they have to stop */
+        for (_, (path, handler)) in self.listeners.lock().unwrap().drain() {
+            let _ = UnixStream::connect(&path);
+            let _ = handler.join();
+        }
+
+        /* Clients see the connection go away as they would if xenstored died */
+        self.disconnect_clients();
+
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
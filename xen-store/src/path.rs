@@ -0,0 +1,321 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    ops::Deref,
+    str::FromStr,
+};
+
+use xen_bindings::bindings::{XENSTORE_ABS_PATH_MAX, XENSTORE_REL_PATH_MAX};
+
+use crate::xs::*;
+
+/* Special paths that can only be watched */
+pub const XS_INTRODUCE_DOMAIN: &str = "@introduceDomain";
+pub const XS_RELEASE_DOMAIN: &str = "@releaseDomain";
+
+/*
+ * A path as xenstored accepts it: absolute, relative to the home of the
+ * connection's domain, or one of the special @ paths.  It dereferences to
+ * &str so it can be given to XenStoreHandle as is.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct XsPath(String);
+
+fn invalid_path(path: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("invalid xenstore path {:?}", path),
+    )
+}
+
+impl XsPath {
+    pub fn new(path: &str) -> Result<Self, std::io::Error> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-/_@".contains(c);
+
+        let (body, max) = match path.strip_prefix('@') {
+            /* Special paths are a single name */
+            Some(name) if name.contains('/') => return Err(invalid_path(path)),
+            Some(name) => (name, XENSTORE_REL_PATH_MAX),
+            None if path.starts_with('/') => (path, XENSTORE_ABS_PATH_MAX),
+            None => (path, XENSTORE_REL_PATH_MAX),
+        };
+
+        if body.is_empty()
+            || path.len() > max as usize
+            || !body.chars().all(valid)
+            || body.contains("//")
+            || (body.ends_with('/') && body != "/")
+        {
+            return Err(invalid_path(path));
+        }
+
+        Ok(XsPath(String::from(path)))
+    }
+
+    pub fn root() -> Self {
+        XsPath(String::from("/"))
+    }
+
+    /* /local/domain/<domid>, the home of the domain */
+    pub fn domain(domid: u32) -> Self {
+        XsPath(format!("/local/domain/{}", domid))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.0.starts_with('/')
+    }
+
+    pub fn is_special(&self) -> bool {
+        self.0.starts_with('@')
+    }
+
+    /* `child` is one or more names, i.e "device" or "device/vif/0" */
+    pub fn join(&self, child: &str) -> Result<Self, std::io::Error> {
+        let child = XsPath::new(child)?;
+
+        if self.is_special() || child.is_absolute() || child.is_special() {
+            return Err(invalid_path(child.as_str()));
+        }
+
+        match self.0.as_str() {
+            "/" => XsPath::new(&format!("/{}", child)),
+            path => XsPath::new(&format!("{}/{}", path, child)),
+        }
+    }
+
+    /* None for the root, special paths and relative paths of a single name */
+    pub fn parent(&self) -> Option<Self> {
+        if self.is_special() {
+            return None;
+        }
+
+        match self.0.rfind('/') {
+            Some(0) if self.0.len() > 1 => Some(XsPath::root()),
+            Some(0) | None => None,
+            Some(index) => Some(XsPath(String::from(&self.0[..index]))),
+        }
+    }
+
+    /* Last name of the path, None for the root */
+    pub fn name(&self) -> Option<&str> {
+        match self.0.rsplit('/').next() {
+            Some("") | None => None,
+            name => name,
+        }
+    }
+
+    /* Whether `self` is `ancestor` or lives below it */
+    pub fn starts_with(&self, ancestor: &XsPath) -> bool {
+        (ancestor.0 == "/" && self.is_absolute())
+            || self.0 == ancestor.0
+            || self
+                .0
+                .strip_prefix(ancestor.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /* Absolute version of a path used by `domid`, whose home relative paths are in */
+    pub fn resolve(&self, domid: u32) -> Self {
+        match self.is_absolute() || self.is_special() {
+            true => self.clone(),
+            false => XsPath(format!("/local/domain/{}/{}", domid, self.0)),
+        }
+    }
+}
+
+impl Deref for XsPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for XsPath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for XsPath {
+    type Err = std::io::Error;
+
+    fn from_str(path: &str) -> Result<Self, std::io::Error> {
+        XsPath::new(path)
+    }
+}
+
+impl fmt::Display for XsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl XenStoreHandle {
+    /* Home of `domid` as xenstored reports it, see get_domain_path() */
+    pub fn domain_path(&self, domid: u32) -> Result<XsPath, std::io::Error> {
+        XsPath::new(&self.get_domain_path(domid)?)
+    }
+
+    /* <home>/device, where the frontends of the domain live */
+    pub fn domain_devices(&self, domid: u32) -> Result<XsPath, std::io::Error> {
+        self.domain_path(domid)?.join("device")
+    }
+
+    /* <home>/backend, where the backends it serves live */
+    pub fn domain_backends(&self, domid: u32) -> Result<XsPath, std::io::Error> {
+        self.domain_path(domid)?.join("backend")
+    }
+
+    /* <home>/name, set by the toolstack */
+    pub fn domain_name(&self, domid: u32) -> Result<String, std::io::Error> {
+        self.read_str(&self.domain_path(domid)?.join("name")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> XsPath {
+        XsPath::new(path).unwrap()
+    }
+
+    #[test]
+    fn valid() {
+        for valid in [
+            "/",
+            "/local/domain/1",
+            "data",
+            "device/vif/0",
+            "/a-b_c@d",
+            XS_INTRODUCE_DOMAIN,
+            XS_RELEASE_DOMAIN,
+        ]
+        .iter()
+        {
+            assert_eq!(path(valid).as_str(), *valid);
+        }
+
+        for invalid in [
+            "",
+            "@",
+            "@release/Domain",
+            "//",
+            "/a//b",
+            "a//b",
+            "/a/",
+            "a/",
+            "/a b",
+            "/a.b",
+            "/é",
+        ]
+        .iter()
+        {
+            let e = XsPath::new(invalid).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn max_length() {
+        let abs = XENSTORE_ABS_PATH_MAX as usize;
+        let rel = XENSTORE_REL_PATH_MAX as usize;
+
+        assert!(XsPath::new(&format!("/{}", "a".repeat(abs - 1))).is_ok());
+        assert!(XsPath::new(&format!("/{}", "a".repeat(abs))).is_err());
+        assert!(XsPath::new(&"a".repeat(rel)).is_ok());
+        assert!(XsPath::new(&"a".repeat(rel + 1)).is_err());
+        assert!(XsPath::new(&format!("@{}", "a".repeat(rel - 1))).is_ok());
+        assert!(XsPath::new(&format!("@{}", "a".repeat(rel))).is_err());
+    }
+
+    #[test]
+    fn kinds() {
+        assert!(path("/a").is_absolute() && !path("/a").is_special());
+        assert!(!path("a").is_absolute() && !path("a").is_special());
+        assert!(!path("@a").is_absolute() && path("@a").is_special());
+        assert_eq!(XsPath::root(), path("/"));
+        assert_eq!(XsPath::domain(3), path("/local/domain/3"));
+    }
+
+    #[test]
+    fn join() {
+        assert_eq!(XsPath::root().join("local").unwrap(), path("/local"));
+        assert_eq!(
+            XsPath::domain(1).join("device/vif/0").unwrap(),
+            path("/local/domain/1/device/vif/0")
+        );
+        assert_eq!(path("data").join("a").unwrap(), path("data/a"));
+
+        for (parent, child) in [
+            ("/a", "/b"),
+            ("/a", "@b"),
+            ("@a", "b"),
+            ("/a", ""),
+            ("/a", "b/"),
+            ("/a", "b//c"),
+        ]
+        .iter()
+        {
+            assert!(path(parent).join(child).is_err());
+        }
+    }
+
+    #[test]
+    fn parent_and_name() {
+        assert_eq!(path("/a/b").parent(), Some(path("/a")));
+        assert_eq!(path("/a").parent(), Some(XsPath::root()));
+        assert_eq!(path("a/b").parent(), Some(path("a")));
+        assert_eq!(XsPath::root().parent(), None);
+        assert_eq!(path("a").parent(), None);
+        assert_eq!(path(XS_RELEASE_DOMAIN).parent(), None);
+
+        assert_eq!(path("/a/b").name(), Some("b"));
+        assert_eq!(path("a").name(), Some("a"));
+        assert_eq!(XsPath::root().name(), None);
+    }
+
+    #[test]
+    fn starts_with() {
+        assert!(path("/a/b").starts_with(&path("/a")));
+        assert!(path("/a").starts_with(&path("/a")));
+        assert!(path("/a").starts_with(&XsPath::root()));
+        assert!(path("a/b").starts_with(&path("a")));
+        assert!(!path("/ab").starts_with(&path("/a")));
+        assert!(!path("/a").starts_with(&path("/a/b")));
+        assert!(!path("a").starts_with(&XsPath::root()));
+        assert!(!path("@a").starts_with(&XsPath::root()));
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(path("data").resolve(1), path("/local/domain/1/data"));
+        assert_eq!(path("/data").resolve(1), path("/data"));
+        assert_eq!(path(XS_RELEASE_DOMAIN).resolve(1), path(XS_RELEASE_DOMAIN));
+    }
+
+    #[test]
+    fn conversions() {
+        let parsed: XsPath = "/local".parse().unwrap();
+
+        assert_eq!(parsed.to_string(), "/local");
+        assert_eq!(&*parsed, "/local");
+        assert_eq!(parsed.as_ref() as &str, "/local");
+        assert!("/local/".parse::<XsPath>().is_err());
+    }
+}
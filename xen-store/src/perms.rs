@@ -0,0 +1,151 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use xen_bindings::bindings::{XS_PERM_NONE, XS_PERM_READ, XS_PERM_WRITE};
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XsAccess {
+    None = XS_PERM_NONE,
+    Read = XS_PERM_READ,
+    Write = XS_PERM_WRITE,
+    Both = XS_PERM_READ | XS_PERM_WRITE,
+}
+
+impl XsAccess {
+    pub fn can_read(&self) -> bool {
+        *self as u32 & XS_PERM_READ != 0
+    }
+
+    pub fn can_write(&self) -> bool {
+        *self as u32 & XS_PERM_WRITE != 0
+    }
+
+    /* Access granted by either of `self` and `other` */
+    pub fn union(&self, other: XsAccess) -> XsAccess {
+        match (
+            self.can_read() || other.can_read(),
+            self.can_write() || other.can_write(),
+        ) {
+            (false, false) => XsAccess::None,
+            (true, false) => XsAccess::Read,
+            (false, true) => XsAccess::Write,
+            (true, true) => XsAccess::Both,
+        }
+    }
+}
+
+/*
+ * One entry of a node's permission list.  The first entry of a list names the
+ * owner of the node, which has full access to it, and the access everybody
+ * else gets unless a later entry is about them.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct XsPermission {
+    pub domid: u32,
+    pub access: XsAccess,
+}
+
+impl XsPermission {
+    pub fn new(domid: u32, access: XsAccess) -> Self {
+        XsPermission { domid, access }
+    }
+}
+
+impl FromStr for XsPermission {
+    type Err = std::io::Error;
+
+    /* "n0", "r1", "w2" or "b3" */
+    fn from_str(perm: &str) -> Result<Self, std::io::Error> {
+        let mut chars = perm.chars();
+        let access = match chars.next() {
+            Some('n') => XsAccess::None,
+            Some('r') => XsAccess::Read,
+            Some('w') => XsAccess::Write,
+            Some('b') => XsAccess::Both,
+            _ => return Err(Error::from(ErrorKind::InvalidData)),
+        };
+        let domid = chars
+            .as_str()
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(XsPermission { domid, access })
+    }
+}
+
+impl fmt::Display for XsPermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            XsAccess::None => 'n',
+            XsAccess::Read => 'r',
+            XsAccess::Write => 'w',
+            XsAccess::Both => 'b',
+        };
+
+        write!(f, "{}{}", access, self.domid)
+    }
+}
+
+/* Same representation as on the wire, i.e "b0" */
+#[cfg(feature = "serde")]
+impl Serialize for XsPermission {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for XsPermission {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let perm = String::deserialize(deserializer)?;
+
+        perm.parse()
+            .map_err(|_| de::Error::custom(format!("invalid permission {}", perm)))
+    }
+}
+
+/* What `domid` is allowed to do with a node that has `perms`, as xenstored sees it */
+pub fn xs_access(perms: &[XsPermission], domid: u32) -> XsAccess {
+    match perms.first() {
+        None => XsAccess::None,
+        Some(owner) if owner.domid == domid || domid == 0 => XsAccess::Both,
+        Some(owner) => {
+            perms[1..]
+                .iter()
+                .find(|perm| perm.domid == domid)
+                .unwrap_or(owner)
+                .access
+        }
+    }
+}
+
+/* Give `domid` `access` on top of what it already has in `perms` */
+pub fn xs_grant(perms: &mut Vec<XsPermission>, domid: u32, access: XsAccess) {
+    match perms.first() {
+        None => perms.extend([
+            XsPermission::new(0, XsAccess::None),
+            XsPermission::new(domid, access),
+        ]),
+        Some(owner) if owner.domid == domid => (),
+        Some(_) => match perms[1..].iter_mut().find(|perm| perm.domid == domid) {
+            Some(perm) => perm.access = perm.access.union(access),
+            None => perms.push(XsPermission::new(domid, access)),
+        },
+    }
+}
@@ -0,0 +1,964 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+/*
+ * The xenstored side of the protocol, without any I/O.  Packets received on a
+ * connection are given to process() and whatever has to be sent back, to that
+ * connection or any other, is collected with take_outgoing().
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Error,
+};
+
+use nix::libc::{E2BIG, EACCES, EAGAIN, EBUSY, EDQUOT, EEXIST, EINVAL, ENOENT, ENOSPC};
+
+use crate::{codec::*, path::*, perms::*, types::*, xs::*};
+
+/* Connection requests made with request() go through, it has no peer */
+const INTERNAL_CONNECTION: u64 = 0;
+
+fn errno(errnum: i32) -> Error {
+    Error::from_raw_os_error(errnum)
+}
+
+/* Turn `path` into an absolute path, relative ones are in the domain's home */
+fn canonical_path(path: &str, domid: u32) -> Result<String, std::io::Error> {
+    /* Tolerate a trailing '/', which XsPath doesn't */
+    let path = match path.strip_suffix('/') {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => path,
+    };
+
+    match XsPath::new(path) {
+        Ok(path) if !path.is_special() => Ok(path.resolve(domid).to_string()),
+        _ => Err(errno(EINVAL)),
+    }
+}
+
+fn parent_path(path: &str) -> Option<&str> {
+    match path.rfind('/') {
+        Some(0) if path.len() > 1 => Some("/"),
+        Some(0) | None => None,
+        Some(index) => Some(&path[..index]),
+    }
+}
+
+/* Whether `path` is `ancestor` or lives below it */
+fn is_below(path: &str, ancestor: &str) -> bool {
+    ancestor == "/"
+        || path == ancestor
+        || path
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/* Limits applied to domains other than dom0, the defaults are those of the C xenstored */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XsQuota {
+    /* Nodes owned by a domain */
+    pub nodes: usize,
+    /* Size of a node's value */
+    pub node_size: usize,
+    /* Entries in a node's permission list */
+    pub perms: usize,
+    /* Watches set by a connection */
+    pub watches: usize,
+    /* Transactions open at the same time on a connection */
+    pub transactions: usize,
+}
+
+impl Default for XsQuota {
+    fn default() -> Self {
+        XsQuota {
+            nodes: 1000,
+            node_size: 2048,
+            perms: 5,
+            watches: 128,
+            transactions: 10,
+        }
+    }
+}
+
+/* Who a request is made on behalf of */
+#[derive(Debug, Clone, Copy)]
+struct Caller {
+    domid: u32,
+    /* Set with XS_SET_TARGET, i.e for a device model running in a stub domain */
+    target: Option<u32>,
+}
+
+impl Caller {
+    fn privileged(&self) -> bool {
+        self.domid == 0
+    }
+
+    fn access(&self, perms: &[XsPermission]) -> XsAccess {
+        let access = xs_access(perms, self.domid);
+
+        match self.target {
+            Some(target) => access.union(xs_access(perms, target)),
+            None => access,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct XsNode {
+    value: Vec<u8>,
+    /* The first entry is the owner and the access granted to everybody else */
+    perms: Vec<XsPermission>,
+    generation: u64,
+}
+
+/*
+ * The main tree and the private copy of each transaction.  `touched` records
+ * the generation of every node an operation looked at, the first time it did,
+ * so that a transaction can tell whether somebody else changed them since.
+ */
+#[derive(Debug, Clone)]
+struct XsTree {
+    nodes: BTreeMap<String, XsNode>,
+    generation: u64,
+    touched: BTreeMap<String, Option<u64>>,
+    /* Nodes that were created, modified or removed */
+    modified: BTreeSet<String>,
+    /* Paths watches should fire for */
+    changed: BTreeSet<String>,
+    quota: XsQuota,
+}
+
+impl XsTree {
+    fn new(quota: XsQuota) -> Self {
+        let mut nodes = BTreeMap::new();
+
+        nodes.insert(
+            String::from("/"),
+            XsNode {
+                value: Vec::new(),
+                perms: vec![XsPermission::new(0, XsAccess::None)],
+                generation: 0,
+            },
+        );
+
+        XsTree {
+            nodes,
+            generation: 0,
+            touched: BTreeMap::new(),
+            modified: BTreeSet::new(),
+            changed: BTreeSet::new(),
+            quota,
+        }
+    }
+
+    fn touch(&mut self, path: &str) {
+        if !self.touched.contains_key(path) {
+            let generation = self.nodes.get(path).map(|node| node.generation);
+            self.touched.insert(String::from(path), generation);
+        }
+    }
+
+    fn modify(&mut self, path: &str) -> &mut XsNode {
+        self.touch(path);
+        self.generation += 1;
+        self.modified.insert(String::from(path));
+
+        let node = self.nodes.get_mut(path).unwrap();
+        node.generation = self.generation;
+        node
+    }
+
+    fn check_perm(&self, path: &str, caller: Caller, write: bool) -> Result<(), std::io::Error> {
+        let access = caller.access(&self.nodes[path].perms);
+
+        match write {
+            true if access.can_write() => Ok(()),
+            false if access.can_read() => Ok(()),
+            _ => Err(errno(EACCES)),
+        }
+    }
+
+    /* Whether `caller` may see `path`, or the closest ancestor if it's gone */
+    fn visible(&self, path: &str, caller: Caller) -> bool {
+        let mut path = path;
+
+        while !self.nodes.contains_key(path) {
+            path = match parent_path(path) {
+                Some(parent) => parent,
+                None => return false,
+            };
+        }
+
+        self.check_perm(path, caller, false).is_ok()
+    }
+
+    fn get(&mut self, path: &str, caller: Caller) -> Result<&XsNode, std::io::Error> {
+        self.touch(path);
+
+        if !self.nodes.contains_key(path) {
+            return Err(errno(ENOENT));
+        }
+        self.check_perm(path, caller, false)?;
+
+        Ok(&self.nodes[path])
+    }
+
+    fn children(&self, path: &str) -> Vec<String> {
+        let prefix = match path {
+            "/" => String::from("/"),
+            _ => format!("{}/", path),
+        };
+
+        self.nodes
+            .range(prefix.clone()..)
+            .map(|(child, _)| child)
+            .take_while(|child| child.starts_with(&prefix))
+            .map(|child| &child[prefix.len()..])
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .map(String::from)
+            .collect()
+    }
+
+    fn owned_by(&self, domid: u32) -> usize {
+        self.nodes
+            .values()
+            .filter(|node| node.perms[0].domid == domid)
+            .count()
+    }
+
+    /* Create `path` and any missing parent, which inherit their parent's permissions */
+    fn create(&mut self, path: &str, caller: Caller) -> Result<(), std::io::Error> {
+        let mut missing = vec![path];
+
+        let mut existing = parent_path(path).ok_or_else(|| errno(EINVAL))?;
+        loop {
+            self.touch(existing);
+            if self.nodes.contains_key(existing) {
+                break;
+            }
+            missing.push(existing);
+            existing = parent_path(existing).ok_or_else(|| errno(EINVAL))?;
+        }
+
+        self.check_perm(existing, caller, true)?;
+        if !caller.privileged() && self.owned_by(caller.domid) + missing.len() > self.quota.nodes {
+            return Err(errno(EDQUOT));
+        }
+        self.modify(existing);
+
+        let mut parent = existing;
+        for path in missing.into_iter().rev() {
+            let mut perms = self.nodes[parent].perms.clone();
+            if !caller.privileged() {
+                perms[0].domid = caller.domid;
+            }
+
+            self.touch(path);
+            self.nodes.insert(
+                String::from(path),
+                XsNode {
+                    value: Vec::new(),
+                    perms,
+                    generation: 0,
+                },
+            );
+            self.modify(path);
+            self.changed.insert(String::from(path));
+            parent = path;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, path: &str, caller: Caller, value: &[u8]) -> Result<(), std::io::Error> {
+        self.touch(path);
+
+        if !caller.privileged() && value.len() > self.quota.node_size {
+            return Err(errno(ENOSPC));
+        }
+
+        if self.nodes.contains_key(path) {
+            self.check_perm(path, caller, true)?;
+        } else {
+            self.create(path, caller)?;
+        }
+
+        self.modify(path).value = value.to_vec();
+        self.changed.insert(String::from(path));
+
+        Ok(())
+    }
+
+    fn mkdir(&mut self, path: &str, caller: Caller) -> Result<(), std::io::Error> {
+        self.touch(path);
+
+        match self.nodes.contains_key(path) {
+            true => Ok(()),
+            false => self.create(path, caller),
+        }
+    }
+
+    fn rm(&mut self, path: &str, caller: Caller) -> Result<(), std::io::Error> {
+        let parent = parent_path(path).ok_or_else(|| errno(EINVAL))?;
+
+        self.touch(path);
+        self.touch(parent);
+
+        /* Removing something that's already gone is fine, as long as the parent is there */
+        if !self.nodes.contains_key(path) {
+            return match self.nodes.contains_key(parent) {
+                true => Ok(()),
+                false => Err(errno(ENOENT)),
+            };
+        }
+        self.check_perm(path, caller, true)?;
+
+        let removed: Vec<String> = self
+            .nodes
+            .keys()
+            .filter(|node| is_below(node, path))
+            .cloned()
+            .collect();
+
+        for node in removed {
+            self.touch(&node);
+            self.nodes.remove(&node);
+            self.modified.insert(node.clone());
+            self.changed.insert(node);
+        }
+        self.modify(parent);
+
+        Ok(())
+    }
+
+    fn set_perms(
+        &mut self,
+        path: &str,
+        caller: Caller,
+        perms: Vec<XsPermission>,
+    ) -> Result<(), std::io::Error> {
+        self.touch(path);
+
+        if perms.is_empty() {
+            return Err(errno(EINVAL));
+        }
+        if !caller.privileged() && perms.len() > self.quota.perms {
+            return Err(errno(ENOSPC));
+        }
+        if !self.nodes.contains_key(path) {
+            return Err(errno(ENOENT));
+        }
+        self.check_perm(path, caller, true)?;
+
+        self.modify(path).perms = perms;
+        self.changed.insert(String::from(path));
+
+        Ok(())
+    }
+
+    /* Forget what was done so far, once it's been dealt with */
+    fn take_changed(&mut self) -> BTreeSet<String> {
+        self.touched.clear();
+        self.modified.clear();
+        std::mem::take(&mut self.changed)
+    }
+}
+
+struct XsServerWatch {
+    /* As given by the client, events are reported relative to it */
+    path: String,
+    node: String,
+    token: String,
+}
+
+struct XsServerConnection {
+    domid: u32,
+    watches: Vec<XsServerWatch>,
+}
+
+struct XsServerTransaction {
+    conn_id: u64,
+    tree: XsTree,
+}
+
+/* What the transport has to act upon, see take_domain_events() */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XsDomainEvent {
+    /* Map the domain's ring at `mfn` and bind to `port` */
+    Introduced { domid: u32, mfn: u64, port: u32 },
+    /* Tear down the domain's ring */
+    Released { domid: u32 },
+}
+
+/*
+ * Permissions, quotas, transactions and watches, the special @introduceDomain
+ * and @releaseDomain ones included.  Transactions keep a copy of the tree and
+ * fail with EAGAIN on commit if a node they looked at changed meanwhile.
+ */
+pub struct XenStoreServer {
+    tree: XsTree,
+    connections: HashMap<u64, XsServerConnection>,
+    transactions: HashMap<u32, XsServerTransaction>,
+    /* Domains introduced and the (mfn, port) of their ring */
+    domains: BTreeMap<u32, (u64, u32)>,
+    /* Those @releaseDomain already fired for, until they resume */
+    shutdown: BTreeSet<u32>,
+    targets: HashMap<u32, u32>,
+    next_conn_id: u64,
+    next_tx_id: u32,
+    /* Watch events to send once the reply to the current request is out */
+    events: Vec<(u64, WatchEvent)>,
+    outgoing: Vec<(u64, XsPacket)>,
+    domain_events: Vec<XsDomainEvent>,
+}
+
+impl Default for XenStoreServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl XenStoreServer {
+    pub fn new() -> Self {
+        Self::with_quota(XsQuota::default())
+    }
+
+    pub fn with_quota(quota: XsQuota) -> Self {
+        let mut connections = HashMap::new();
+
+        connections.insert(
+            INTERNAL_CONNECTION,
+            XsServerConnection {
+                domid: 0,
+                watches: Vec::new(),
+            },
+        );
+
+        XenStoreServer {
+            tree: XsTree::new(quota),
+            connections,
+            transactions: HashMap::new(),
+            domains: BTreeMap::new(),
+            shutdown: BTreeSet::new(),
+            targets: HashMap::new(),
+            next_conn_id: INTERNAL_CONNECTION + 1,
+            next_tx_id: 0,
+            events: Vec::new(),
+            outgoing: Vec::new(),
+            domain_events: Vec::new(),
+        }
+    }
+
+    pub fn quota(&self) -> XsQuota {
+        self.tree.quota
+    }
+
+    /* A new connection whose requests are made on behalf of `domid` */
+    pub fn connect(&mut self, domid: u32) -> u64 {
+        let conn_id = self.next_conn_id;
+
+        self.next_conn_id += 1;
+        self.connections.insert(
+            conn_id,
+            XsServerConnection {
+                domid,
+                watches: Vec::new(),
+            },
+        );
+
+        conn_id
+    }
+
+    /* Watches and transactions of the connection go away with it */
+    pub fn disconnect(&mut self, conn_id: u64) {
+        if conn_id != INTERNAL_CONNECTION {
+            self.connections.remove(&conn_id);
+            self.transactions.retain(|_, tx| tx.conn_id != conn_id);
+        }
+    }
+
+    pub fn domid(&self, conn_id: u64) -> Option<u32> {
+        self.connections.get(&conn_id).map(|conn| conn.domid)
+    }
+
+    pub fn is_introduced(&self, domid: u32) -> bool {
+        self.domains.contains_key(&domid)
+    }
+
+    pub fn introduced(&self) -> Vec<u32> {
+        self.domains.keys().copied().collect()
+    }
+
+    /* Answer a packet received on `conn_id`, with XS_ERROR if the request failed */
+    pub fn process(&mut self, conn_id: u64, packet: &XsPacket) {
+        let (r#type, req_id, tx_id) = (packet.r#type, packet.req_id, packet.tx_id);
+
+        /* Not connect()ed, or already gone: there is nobody to answer */
+        if !self.connections.contains_key(&conn_id) {
+            return;
+        }
+
+        let reply = XsRequest::from_packet(packet)
+            .map_err(|_| errno(EINVAL))
+            .and_then(|request| self.handle(conn_id, tx_id, request))
+            .and_then(|reply| reply.to_packet(r#type, req_id, tx_id))
+            .or_else(|e| {
+                XsReply::Error(e.raw_os_error().unwrap_or(EINVAL)).to_packet(r#type, req_id, tx_id)
+            });
+
+        if let Ok(reply) = reply {
+            self.outgoing.push((conn_id, reply));
+        }
+
+        self.commit_changes();
+    }
+
+    /* Make a request as dom0, i.e to set up the tree */
+    pub fn request(&mut self, request: XsRequest) -> Result<XsReply, std::io::Error> {
+        let reply = self.handle(INTERNAL_CONNECTION, XBT_NULL, request);
+
+        self.commit_changes();
+
+        reply
+    }
+
+    /* Packets to send, in order, along with the connection they go to */
+    pub fn take_outgoing(&mut self) -> Vec<(u64, XsPacket)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn take_domain_events(&mut self) -> Vec<XsDomainEvent> {
+        std::mem::take(&mut self.domain_events)
+    }
+
+    /* As XS_RELEASE, for domains found to be gone */
+    pub fn release_domain(&mut self, domid: u32) -> Result<(), std::io::Error> {
+        self.release(domid)?;
+        self.flush_events();
+
+        Ok(())
+    }
+
+    /*
+     * For domains that shut down or crashed: watchers learn about it through
+     * @releaseDomain but the domain stays introduced, it may resume.
+     */
+    pub fn shutdown_domain(&mut self, domid: u32) -> Result<(), std::io::Error> {
+        if !self.is_introduced(domid) {
+            return Err(errno(ENOENT));
+        }
+        if self.shutdown.insert(domid) {
+            self.fire_special(XS_RELEASE_DOMAIN);
+            self.flush_events();
+        }
+
+        Ok(())
+    }
+
+    fn release(&mut self, domid: u32) -> Result<(), std::io::Error> {
+        self.domains.remove(&domid).ok_or_else(|| errno(ENOENT))?;
+        self.shutdown.remove(&domid);
+        self.targets.remove(&domid);
+        self.domain_events.push(XsDomainEvent::Released { domid });
+        self.fire_special(XS_RELEASE_DOMAIN);
+
+        Ok(())
+    }
+
+    fn caller(&self, conn_id: u64) -> Caller {
+        let domid = self.connections[&conn_id].domid;
+
+        Caller {
+            domid,
+            target: self.targets.get(&domid).copied(),
+        }
+    }
+
+    /* Changes to the main tree are visible right away */
+    fn commit_changes(&mut self) {
+        let changed = self.tree.take_changed();
+
+        self.queue_events(&changed);
+        self.flush_events();
+    }
+
+    fn queue_events(&mut self, changed: &BTreeSet<String>) {
+        for (conn_id, conn) in self.connections.iter() {
+            let domain_path = format!("/local/domain/{}/", conn.domid);
+            let caller = self.caller(*conn_id);
+
+            for watch in conn.watches.iter() {
+                for path in changed.iter() {
+                    let fires = match watch.node.starts_with('@') {
+                        true => *path == watch.node,
+                        false => is_below(path, &watch.node) && self.tree.visible(path, caller),
+                    };
+                    if !fires {
+                        continue;
+                    }
+
+                    let path = match watch.path.starts_with('/') || watch.path.starts_with('@') {
+                        true => path.as_str(),
+                        false => path.strip_prefix(&domain_path).unwrap_or(path),
+                    };
+                    self.events.push((
+                        *conn_id,
+                        WatchEvent {
+                            path: String::from(path),
+                            token: watch.token.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+    }
+
+    fn flush_events(&mut self) {
+        for (conn_id, event) in std::mem::take(&mut self.events) {
+            if conn_id == INTERNAL_CONNECTION {
+                continue;
+            }
+            if let Ok(packet) = XsReply::WatchEvent(event).to_packet(XS_WATCH_EVENT, 0, 0) {
+                self.outgoing.push((conn_id, packet));
+            }
+        }
+    }
+
+    fn fire_special(&mut self, path: &str) {
+        self.queue_events(&BTreeSet::from([String::from(path)]));
+    }
+
+    /* The tree a request operates on, either the main one or a transaction's */
+    fn tree(&mut self, conn_id: u64, tx_id: u32) -> Result<&mut XsTree, std::io::Error> {
+        if tx_id == XBT_NULL {
+            return Ok(&mut self.tree);
+        }
+
+        match self.transactions.get_mut(&tx_id) {
+            Some(tx) if tx.conn_id == conn_id => Ok(&mut tx.tree),
+            _ => Err(errno(ENOENT)),
+        }
+    }
+
+    fn transaction_end(&mut self, tx_id: u32, commit: bool) -> Result<(), std::io::Error> {
+        let mut tx = self
+            .transactions
+            .remove(&tx_id)
+            .ok_or_else(|| errno(ENOENT))?;
+
+        if !commit {
+            return Ok(());
+        }
+
+        let conflict = tx.tree.touched.iter().any(|(path, generation)| {
+            self.tree.nodes.get(path).map(|node| node.generation) != *generation
+        });
+        if conflict {
+            return Err(errno(EAGAIN));
+        }
+
+        for path in tx.tree.modified.iter() {
+            match tx.tree.nodes.remove(path) {
+                Some(mut node) => {
+                    self.tree.generation += 1;
+                    node.generation = self.tree.generation;
+                    self.tree.nodes.insert(path.clone(), node);
+                }
+                None => {
+                    self.tree.nodes.remove(path);
+                }
+            }
+        }
+        self.queue_events(&tx.tree.changed);
+
+        Ok(())
+    }
+
+    fn directory_part(
+        &mut self,
+        conn_id: u64,
+        tx_id: u32,
+        path: &str,
+        offset: usize,
+    ) -> Result<XsReply, std::io::Error> {
+        let caller = self.caller(conn_id);
+        let path = canonical_path(path, caller.domid)?;
+        let tree = self.tree(conn_id, tx_id)?;
+        let generation = tree.get(&path, caller)?.generation.to_string();
+        let mut children = tree.children(&path).into_iter();
+
+        /* `offset` is in bytes, into the list of NUL terminated children */
+        let mut skipped = 0;
+        while skipped < offset {
+            skipped += children.next().ok_or_else(|| errno(EINVAL))?.len() + 1;
+        }
+        if skipped != offset {
+            return Err(errno(EINVAL));
+        }
+
+        /* Keep room for the extra NUL that marks the last chunk */
+        let mut room = XENSTORE_PAYLOAD_MAX as usize - generation.len() - 2;
+        let mut chunk = Vec::new();
+        let mut children = children.peekable();
+        while let Some(child) = children.next_if(|child| child.len() < room) {
+            room -= child.len() + 1;
+            chunk.push(child);
+        }
+
+        match (chunk.is_empty(), children.peek().is_none()) {
+            (true, false) => Err(errno(E2BIG)),
+            (_, last) => Ok(XsReply::DirectoryPart {
+                generation,
+                children: chunk,
+                last,
+            }),
+        }
+    }
+
+    fn handle(
+        &mut self,
+        conn_id: u64,
+        tx_id: u32,
+        request: XsRequest,
+    ) -> Result<XsReply, std::io::Error> {
+        let caller = self.caller(conn_id);
+        let domid = caller.domid;
+
+        match request {
+            XsRequest::Read { path } => {
+                let path = canonical_path(&path, domid)?;
+                let tree = self.tree(conn_id, tx_id)?;
+                Ok(XsReply::Value(tree.get(&path, caller)?.value.clone()))
+            }
+            XsRequest::Write { path, value } => {
+                let path = canonical_path(&path, domid)?;
+                self.tree(conn_id, tx_id)?.write(&path, caller, &value)?;
+                Ok(XsReply::Ok)
+            }
+            XsRequest::Directory { path } => {
+                let path = canonical_path(&path, domid)?;
+                let tree = self.tree(conn_id, tx_id)?;
+                tree.get(&path, caller)?;
+                let children = tree.children(&path);
+                let len: usize = children.iter().map(|child| child.len() + 1).sum();
+                match len > XENSTORE_PAYLOAD_MAX as usize {
+                    true => Err(errno(E2BIG)),
+                    false => Ok(XsReply::Directory(children)),
+                }
+            }
+            XsRequest::DirectoryPart { path, offset } => {
+                self.directory_part(conn_id, tx_id, &path, offset)
+            }
+            XsRequest::GetPerms { path } => {
+                let path = canonical_path(&path, domid)?;
+                let tree = self.tree(conn_id, tx_id)?;
+                Ok(XsReply::Perms(tree.get(&path, caller)?.perms.clone()))
+            }
+            XsRequest::SetPerms { path, perms } => {
+                let path = canonical_path(&path, domid)?;
+                let tree = self.tree(conn_id, tx_id)?;
+                tree.set_perms(&path, caller, perms)?;
+                Ok(XsReply::Ok)
+            }
+            XsRequest::Mkdir { path } => {
+                let path = canonical_path(&path, domid)?;
+                self.tree(conn_id, tx_id)?.mkdir(&path, caller)?;
+                Ok(XsReply::Ok)
+            }
+            XsRequest::Rm { path } => {
+                let path = canonical_path(&path, domid)?;
+                self.tree(conn_id, tx_id)?.rm(&path, caller)?;
+                Ok(XsReply::Ok)
+            }
+            XsRequest::Watch { path, token } => {
+                let node = match path.starts_with('@') {
+                    true => path.clone(),
+                    false => canonical_path(&path, domid)?,
+                };
+                let quota = self.tree.quota.watches;
+                let conn = self.connections.get_mut(&conn_id).unwrap();
+                if conn
+                    .watches
+                    .iter()
+                    .any(|watch| watch.path == path && watch.token == token)
+                {
+                    return Err(errno(EEXIST));
+                }
+                if !caller.privileged() && conn.watches.len() >= quota {
+                    return Err(errno(E2BIG));
+                }
+                conn.watches.push(XsServerWatch {
+                    path: path.clone(),
+                    node,
+                    token: token.clone(),
+                });
+                /* The first event goes out right after the reply */
+                self.events.push((conn_id, WatchEvent { path, token }));
+                Ok(XsReply::Ok)
+            }
+            XsRequest::Unwatch { path, token } => {
+                let conn = self.connections.get_mut(&conn_id).unwrap();
+                let index = conn
+                    .watches
+                    .iter()
+                    .position(|watch| watch.path == path && watch.token == token)
+                    .ok_or_else(|| errno(ENOENT))?;
+                conn.watches.remove(index);
+                Ok(XsReply::Ok)
+            }
+            XsRequest::ResetWatches => {
+                self.connections.get_mut(&conn_id).unwrap().watches.clear();
+                Ok(XsReply::Ok)
+            }
+            XsRequest::TransactionStart => {
+                if tx_id != XBT_NULL {
+                    return Err(errno(EBUSY));
+                }
+                let open = self
+                    .transactions
+                    .values()
+                    .filter(|tx| tx.conn_id == conn_id)
+                    .count();
+                if !caller.privileged() && open >= self.tree.quota.transactions {
+                    return Err(errno(ENOSPC));
+                }
+                self.next_tx_id += 1;
+                let tx_id = self.next_tx_id;
+                let mut tree = self.tree.clone();
+                tree.take_changed();
+                self.transactions
+                    .insert(tx_id, XsServerTransaction { conn_id, tree });
+                Ok(XsReply::TransactionStarted(tx_id))
+            }
+            XsRequest::TransactionEnd { commit } => {
+                match self.transactions.get(&tx_id) {
+                    Some(tx) if tx.conn_id == conn_id => (),
+                    _ => return Err(errno(ENOENT)),
+                }
+                self.transaction_end(tx_id, commit)?;
+                Ok(XsReply::Ok)
+            }
+            XsRequest::GetDomainPath { domid } => {
+                Ok(XsReply::DomainPath(XsPath::domain(domid).to_string()))
+            }
+            XsRequest::Introduce { .. }
+            | XsRequest::Release { .. }
+            | XsRequest::IsDomainIntroduced { .. }
+            | XsRequest::Resume { .. }
+            | XsRequest::SetTarget { .. }
+                if !caller.privileged() =>
+            {
+                Err(errno(EACCES))
+            }
+            XsRequest::Introduce { domid, mfn, port } => {
+                match self.domains.get(&domid) {
+                    /* The toolstack may introduce a domain again, i.e after a restart */
+                    Some(ring) if *ring == (mfn, port) => return Ok(XsReply::Ok),
+                    Some(_) => return Err(errno(EEXIST)),
+                    None => (),
+                }
+                self.domains.insert(domid, (mfn, port));
+                self.domain_events
+                    .push(XsDomainEvent::Introduced { domid, mfn, port });
+                self.fire_special(XS_INTRODUCE_DOMAIN);
+                Ok(XsReply::Ok)
+            }
+            XsRequest::Release { domid } => {
+                self.release(domid)?;
+                Ok(XsReply::Ok)
+            }
+            XsRequest::IsDomainIntroduced { domid } => {
+                Ok(XsReply::IsDomainIntroduced(self.is_introduced(domid)))
+            }
+            XsRequest::Resume { domid } => match self.is_introduced(domid) {
+                true => {
+                    self.shutdown.remove(&domid);
+                    Ok(XsReply::Ok)
+                }
+                false => Err(errno(ENOENT)),
+            },
+            XsRequest::SetTarget { domid, target } => {
+                self.targets.insert(domid, target);
+                Ok(XsReply::Ok)
+            }
+            XsRequest::Control { .. } => Err(errno(EINVAL)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A dom0 connection watching @releaseDomain */
+    fn watcher(server: &mut XenStoreServer) -> u64 {
+        let conn_id = server.connect(0);
+        let watch = XsRequest::Watch {
+            path: String::from(XS_RELEASE_DOMAIN),
+            token: String::from("released"),
+        };
+
+        server.process(conn_id, &watch.to_packet(1, XBT_NULL).unwrap());
+        server.take_outgoing();
+        conn_id
+    }
+
+    fn fired(server: &mut XenStoreServer) -> usize {
+        server
+            .take_outgoing()
+            .iter()
+            .filter(|(_, packet)| packet.r#type == XS_WATCH_EVENT)
+            .count()
+    }
+
+    #[test]
+    fn shutdown_keeps_domain() {
+        let mut server = XenStoreServer::new();
+        watcher(&mut server);
+
+        let introduce = XsRequest::Introduce {
+            domid: 1,
+            mfn: 0x1000,
+            port: 5,
+        };
+        server.request(introduce).unwrap();
+        server.take_domain_events();
+
+        server.shutdown_domain(1).unwrap();
+        assert_eq!(fired(&mut server), 1);
+        /* Only once, however many times it is found shut down */
+        server.shutdown_domain(1).unwrap();
+        assert_eq!(fired(&mut server), 0);
+
+        /* i.e a save/restore or a migration that didn't go through */
+        assert!(server.is_introduced(1));
+        assert!(server.take_domain_events().is_empty());
+        assert_eq!(
+            server.request(XsRequest::Resume { domid: 1 }).unwrap(),
+            XsReply::Ok
+        );
+
+        /* Shutting down again after resuming is another event */
+        server.shutdown_domain(1).unwrap();
+        assert_eq!(fired(&mut server), 1);
+
+        server.release_domain(1).unwrap();
+        assert_eq!(fired(&mut server), 1);
+        assert_eq!(
+            server.take_domain_events(),
+            [XsDomainEvent::Released { domid: 1 }]
+        );
+        let e = server.request(XsRequest::Resume { domid: 1 }).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(ENOENT));
+        let e = server.shutdown_domain(1).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(ENOENT));
+    }
+}
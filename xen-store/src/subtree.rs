@@ -0,0 +1,150 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{collections::BTreeMap, io::ErrorKind};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{perms::*, xs::*};
+
+/*
+ * A node and everything below it, i.e a snapshot of /local/domain/<id>.  With
+ * the "serde" feature values are serialized as strings when they are valid
+ * UTF-8 and as byte arrays otherwise, permissions as "b0", "r1" and so on.
+ */
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XsSubtree {
+    #[cfg_attr(feature = "serde", serde(default, with = "value"))]
+    pub value: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub perms: Vec<XsPermission>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "BTreeMap::is_empty")
+    )]
+    pub children: BTreeMap<String, XsSubtree>,
+}
+
+fn join(path: &str, child: &str) -> String {
+    match path.strip_suffix('/') {
+        Some(parent) => format!("{}/{}", parent, child),
+        None => format!("{}/{}", path, child),
+    }
+}
+
+impl XenStoreTransaction<'_> {
+    pub fn export(&self, path: &str) -> Result<XsSubtree, std::io::Error> {
+        let mut children = BTreeMap::new();
+
+        for name in self.directory(path)? {
+            let child = self.export(&join(path, &name))?;
+            children.insert(name, child);
+        }
+
+        Ok(XsSubtree {
+            value: self.read_bytes(path)?,
+            perms: self.get_perms(path)?,
+            children,
+        })
+    }
+
+    /* Replace whatever is at `path` with `subtree` */
+    pub fn import(&self, path: &str, subtree: &XsSubtree) -> Result<(), std::io::Error> {
+        /* The root can't be removed, what's below it is instead */
+        if path == "/" {
+            for name in self.directory(path)? {
+                self.rm(&join(path, &name))?;
+            }
+        } else {
+            match self.rm(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+
+        self.import_node(path, subtree)
+    }
+
+    fn import_node(&self, path: &str, subtree: &XsSubtree) -> Result<(), std::io::Error> {
+        self.write_bytes(path, &subtree.value)?;
+        if !subtree.perms.is_empty() {
+            self.set_perms(path, &subtree.perms)?;
+        }
+
+        for (name, child) in subtree.children.iter() {
+            self.import_node(&join(path, name), child)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl XenStoreHandle {
+    /* Values and permissions of `path` and below, as seen at one point in time */
+    pub fn export(&self, path: &str) -> Result<XsSubtree, std::io::Error> {
+        self.with_transaction(|transaction| transaction.export(path))
+    }
+
+    /* Recreate `subtree` at `path` in one transaction, see XenStoreTransaction::import() */
+    pub fn import(&self, path: &str, subtree: &XsSubtree) -> Result<(), std::io::Error> {
+        self.with_transaction(|transaction| transaction.import(path, subtree))
+    }
+}
+
+#[cfg(feature = "serde")]
+mod value {
+    use std::fmt;
+
+    use serde::{
+        de::{Error, SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(value) {
+            Ok(value) => serializer.serialize_str(value),
+            Err(_) => serializer.serialize_bytes(value),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+
+    struct ValueVisitor;
+
+    impl<'de> Visitor<'de> for ValueVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a string or an array of bytes")
+        }
+
+        fn visit_str<E: Error>(self, value: &str) -> Result<Vec<u8>, E> {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn visit_bytes<E: Error>(self, value: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(value.to_vec())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut value = Vec::new();
+
+            while let Some(byte) = seq.next_element()? {
+                value.push(byte);
+            }
+
+            Ok(value)
+        }
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Read, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        io::{AsRawFd, RawFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::types::*;
+
+/* How to reach xenstored */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XenStoreTransport {
+    /* Unix socket of a xenstored running in the same domain, i.e dom0 */
+    Socket(PathBuf),
+    /* xenbus character device, works in any domain including domUs */
+    Device(PathBuf),
+}
+
+impl XenStoreTransport {
+    /* Pick the transport based on what `path` is, as libxenstore does */
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let path = path.as_ref();
+
+        if path.metadata()?.file_type().is_socket() {
+            Ok(XenStoreTransport::Socket(path.to_path_buf()))
+        } else {
+            Ok(XenStoreTransport::Device(path.to_path_buf()))
+        }
+    }
+
+    /*
+     * The transports to try, in order, when none was specified.  Setting
+     * XENSTORED_PATH in the environment overrides the defaults.
+     */
+    pub fn candidates() -> Result<Vec<Self>, std::io::Error> {
+        if let Some(path) = env::var_os(XENSTORED_PATH_ENV) {
+            return Ok(vec![Self::from_path(path)?]);
+        }
+
+        Ok(vec![
+            XenStoreTransport::Socket(PathBuf::from(XENSTORED_SOCKET)),
+            XenStoreTransport::Device(PathBuf::from(XENBUS_DEVICE)),
+        ])
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            XenStoreTransport::Socket(path) | XenStoreTransport::Device(path) => path,
+        }
+    }
+
+    pub(crate) fn connect(&self) -> Result<XenStoreStream, std::io::Error> {
+        match self {
+            XenStoreTransport::Socket(path) => {
+                Ok(XenStoreStream::Socket(UnixStream::connect(path)?))
+            }
+            XenStoreTransport::Device(path) => Ok(XenStoreStream::Device(
+                OpenOptions::new().read(true).write(true).open(path)?,
+            )),
+        }
+    }
+
+    /* Connect to the first of candidates() that works */
+    pub(crate) fn connect_any() -> Result<(Self, XenStoreStream), std::io::Error> {
+        let mut error = Error::from(ErrorKind::NotFound);
+
+        for transport in Self::candidates()? {
+            match transport.connect() {
+                Ok(stream) => return Ok((transport, stream)),
+                Err(e) => error = e,
+            }
+        }
+
+        Err(error)
+    }
+}
+
+/*
+ * Both transports carry the same xsd_sockmsg framing, only the file
+ * descriptor behind them differs.
+ */
+pub(crate) enum XenStoreStream {
+    Socket(UnixStream),
+    Device(File),
+}
+
+impl XenStoreStream {
+    pub(crate) fn try_clone(&self) -> Result<Self, std::io::Error> {
+        match self {
+            XenStoreStream::Socket(socket) => Ok(XenStoreStream::Socket(socket.try_clone()?)),
+            XenStoreStream::Device(file) => Ok(XenStoreStream::Device(file.try_clone()?)),
+        }
+    }
+}
+
+impl Read for XenStoreStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self {
+            XenStoreStream::Socket(socket) => socket.read(buf),
+            XenStoreStream::Device(file) => file.read(buf),
+        }
+    }
+}
+
+impl Write for XenStoreStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        match self {
+            XenStoreStream::Socket(socket) => socket.write(buf),
+            XenStoreStream::Device(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            XenStoreStream::Socket(socket) => socket.flush(),
+            XenStoreStream::Device(file) => file.flush(),
+        }
+    }
+}
+
+impl AsRawFd for XenStoreStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            XenStoreStream::Socket(socket) => socket.as_raw_fd(),
+            XenStoreStream::Device(file) => file.as_raw_fd(),
+        }
+    }
+}
@@ -10,43 +10,78 @@
 
 use std::io;
 
-use nix::libc::{iovec, E2BIG};
+use nix::libc::{
+    E2BIG, EACCES, EAGAIN, EBUSY, EDQUOT, EEXIST, EINVAL, EIO, EISCONN, EISDIR, ENOENT, ENOMEM,
+    ENOSPC, ENOSYS, ENOTEMPTY, EPERM, EROFS,
+};
 
 pub const XENSTORED_SOCKET: &str = "/var/run/xenstored/socket";
+pub const XENBUS_DEVICE: &str = "/dev/xen/xenbus";
+/* Environment variable overriding the path of the socket or device */
+pub const XENSTORED_PATH_ENV: &str = "XENSTORED_PATH";
 pub const XENSTORE_PAYLOAD_MAX: u32 = 4096;
+/* Transaction ID used for requests that are not part of a transaction */
+pub const XBT_NULL: u32 = 0;
 
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
-// xen/include/public/io/xs_wire.h::struct xsd_sockmsg
-pub(crate) struct XenSocketMessage {
-    pub r#type: u32,
-    pub req_id: u32,
-    pub tx_id: u32,
-    pub len: u32,
+// xen/include/public/io/xs_wire.h::xsd_errors
+const XSD_ERRORS: [(&str, i32); 17] = [
+    ("EINVAL", EINVAL),
+    ("EACCES", EACCES),
+    ("EEXIST", EEXIST),
+    ("EISDIR", EISDIR),
+    ("ENOENT", ENOENT),
+    ("ENOMEM", ENOMEM),
+    ("ENOSPC", ENOSPC),
+    ("EIO", EIO),
+    ("ENOTEMPTY", ENOTEMPTY),
+    ("ENOSYS", ENOSYS),
+    ("EROFS", EROFS),
+    ("EBUSY", EBUSY),
+    ("EAGAIN", EAGAIN),
+    ("EISCONN", EISCONN),
+    ("E2BIG", E2BIG),
+    ("EPERM", EPERM),
+    /* Not part of xs_wire.h, used by oxenstored when a quota is exceeded */
+    ("EQUOTA", EDQUOT),
+];
+
+/*
+ * Turn the payload of an XS_ERROR reply, i.e "ENOENT\0", into the matching
+ * errno.  Unknown strings are reported as EINVAL, the same as libxenstore.
+ */
+pub(crate) fn xsd_errno(body: &str) -> i32 {
+    let name = body.trim_end_matches('\0');
+
+    XSD_ERRORS
+        .iter()
+        .find(|(errstring, _)| *errstring == name)
+        .map_or(EINVAL, |(_, errnum)| *errnum)
 }
 
-impl XenSocketMessage {
-    #[allow(clippy::ptr_arg)]
-    pub(crate) fn new(r#type: u32, iovec_buffers: &mut Vec<iovec>) -> Result<Self, std::io::Error> {
-        let msg = XenSocketMessage {
-            r#type,
-            req_id: 0,
-            tx_id: 0,
-            len: iovec_buffers
-                .iter()
-                .fold(0, |acc, iovec| acc + iovec.iov_len as u32),
-        };
-
-        if msg.len > XENSTORE_PAYLOAD_MAX {
-            return Err(io::Error::from_raw_os_error(E2BIG));
-        }
+/* The reverse of xsd_errno(), for answering requests */
+pub(crate) fn xsd_error_name(errno: i32) -> &'static str {
+    XSD_ERRORS
+        .iter()
+        .find(|(_, errnum)| *errnum == errno)
+        .map_or("EINVAL", |(errstring, _)| errstring)
+}
 
-        Ok(msg)
-    }
+/* Replies other than the value of a node are always text */
+pub(crate) fn body_to_string(body: Vec<u8>) -> Result<String, std::io::Error> {
+    String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-#[derive(Debug, Default, Clone)]
-pub(crate) struct XenStoreMessage {
-    pub r#type: u32,
-    pub body: String,
+/* Lay out `args` as a sequence of NUL terminated strings */
+pub(crate) fn xs_payload(args: &[&[u8]]) -> Result<Vec<u8>, std::io::Error> {
+    let mut payload = Vec::new();
+
+    for arg in args {
+        if arg.contains(&0) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        payload.extend_from_slice(arg);
+        payload.push(0);
+    }
+
+    Ok(payload)
 }
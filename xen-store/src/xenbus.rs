@@ -0,0 +1,324 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{Error, ErrorKind},
+    os::unix::io::RawFd,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use xen_bindings::bindings::{
+    xenbus_state_XenbusStateClosed, xenbus_state_XenbusStateClosing,
+    xenbus_state_XenbusStateConnected, xenbus_state_XenbusStateInitWait,
+    xenbus_state_XenbusStateInitialised, xenbus_state_XenbusStateInitialising,
+    xenbus_state_XenbusStateReconfigured, xenbus_state_XenbusStateReconfiguring,
+    xenbus_state_XenbusStateUnknown,
+};
+
+use crate::{path::*, xs::*};
+
+pub const XENBUS_HOTPLUG_CONNECTED: &str = "connected";
+
+/* Tells apart the watch tokens of drivers sharing a handle */
+static NEXT_DRIVER_ID: AtomicU64 = AtomicU64::new(0);
+
+// xen/include/public/io/xenbus.h::enum xenbus_state
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XenbusState {
+    Unknown = xenbus_state_XenbusStateUnknown,
+    Initialising = xenbus_state_XenbusStateInitialising,
+    InitWait = xenbus_state_XenbusStateInitWait,
+    Initialised = xenbus_state_XenbusStateInitialised,
+    Connected = xenbus_state_XenbusStateConnected,
+    Closing = xenbus_state_XenbusStateClosing,
+    Closed = xenbus_state_XenbusStateClosed,
+    Reconfiguring = xenbus_state_XenbusStateReconfiguring,
+    Reconfigured = xenbus_state_XenbusStateReconfigured,
+}
+
+const XENBUS_STATES: [XenbusState; 9] = [
+    XenbusState::Unknown,
+    XenbusState::Initialising,
+    XenbusState::InitWait,
+    XenbusState::Initialised,
+    XenbusState::Connected,
+    XenbusState::Closing,
+    XenbusState::Closed,
+    XenbusState::Reconfiguring,
+    XenbusState::Reconfigured,
+];
+
+impl TryFrom<u32> for XenbusState {
+    type Error = std::io::Error;
+
+    fn try_from(state: u32) -> Result<Self, std::io::Error> {
+        XENBUS_STATES
+            .iter()
+            .find(|xenbus_state| **xenbus_state as u32 == state)
+            .copied()
+            .ok_or_else(|| Error::from(ErrorKind::InvalidData))
+    }
+}
+
+impl fmt::Display for XenbusState {
+    /* The way it's stored in the "state" node */
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", *self as u32)
+    }
+}
+
+impl XenbusState {
+    /* A missing "state" node is the same as XenbusStateUnknown */
+    pub fn read(xsh: &XenStoreHandle, path: &XsPath) -> Result<Self, std::io::Error> {
+        match xsh.read_str(&path.join("state")?) {
+            Ok(state) => state
+                .trim()
+                .parse::<u32>()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+                .and_then(XenbusState::try_from),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(XenbusState::Unknown),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write(&self, xsh: &XenStoreHandle, path: &XsPath) -> Result<(), std::io::Error> {
+        xsh.write_str(&path.join("state")?, &self.to_string())
+    }
+}
+
+/* Where each end of a split driver lives in the store */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XenbusDevice {
+    pub device_type: String,
+    pub frontend_domid: u32,
+    pub backend_domid: u32,
+    pub devid: u32,
+}
+
+impl XenbusDevice {
+    pub fn new(device_type: &str, frontend_domid: u32, backend_domid: u32, devid: u32) -> Self {
+        XenbusDevice {
+            device_type: String::from(device_type),
+            frontend_domid,
+            backend_domid,
+            devid,
+        }
+    }
+
+    /* Find out who serves the device from what the toolstack put in the frontend */
+    pub fn from_frontend(
+        xsh: &XenStoreHandle,
+        device_type: &str,
+        frontend_domid: u32,
+        devid: u32,
+    ) -> Result<Self, std::io::Error> {
+        let mut device = Self::new(device_type, frontend_domid, 0, devid);
+
+        device.backend_domid = xsh
+            .read_str(&device.frontend_path(xsh)?.join("backend-id")?)?
+            .trim()
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(device)
+    }
+
+    /* <frontend home>/device/<type>/<devid> */
+    pub fn frontend_path(&self, xsh: &XenStoreHandle) -> Result<XsPath, std::io::Error> {
+        xsh.domain_devices(self.frontend_domid)?
+            .join(&self.device_type)?
+            .join(&self.devid.to_string())
+    }
+
+    /* <backend home>/backend/<type>/<frontend>/<devid> */
+    pub fn backend_path(&self, xsh: &XenStoreHandle) -> Result<XsPath, std::io::Error> {
+        xsh.domain_backends(self.backend_domid)?
+            .join(&self.device_type)?
+            .join(&self.frontend_domid.to_string())?
+            .join(&self.devid.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XenbusRole {
+    Frontend,
+    Backend,
+}
+
+type XenbusCallback<'a> =
+    Box<dyn FnMut(&XenbusDriver<'a>, XenbusState, XenbusState) -> Result<(), std::io::Error> + 'a>;
+
+/*
+ * One end of a split driver.  The peer's "state" node is watched and the
+ * callbacks registered with on_transition() are run, in order, each time it
+ * changes.  Events are only processed from process_event(), which blocks.
+ */
+pub struct XenbusDriver<'a> {
+    xsh: &'a XenStoreHandle,
+    device: XenbusDevice,
+    role: XenbusRole,
+    /* Both ends are looked up once, homes don't move */
+    frontend_path: XsPath,
+    backend_path: XsPath,
+    watch: Watch,
+    peer_state: XenbusState,
+    callbacks: Vec<XenbusCallback<'a>>,
+}
+
+impl<'a> XenbusDriver<'a> {
+    pub fn new(
+        xsh: &'a XenStoreHandle,
+        device: XenbusDevice,
+        role: XenbusRole,
+    ) -> Result<Self, std::io::Error> {
+        let frontend_path = device.frontend_path(xsh)?;
+        let backend_path = device.backend_path(xsh)?;
+        let peer_path = match role {
+            XenbusRole::Frontend => &backend_path,
+            XenbusRole::Backend => &frontend_path,
+        };
+        let state_path = peer_path.join("state")?;
+        let driver_id = NEXT_DRIVER_ID.fetch_add(1, Ordering::Relaxed);
+        let watch =
+            xsh.create_watch(&state_path, &format!("xenbus:{}:{}", driver_id, state_path))?;
+
+        /* Consume the event xenstored fires when the watch is set */
+        watch.read_watch()?;
+
+        Ok(XenbusDriver {
+            xsh,
+            device,
+            role,
+            peer_state: XenbusState::read(xsh, peer_path)?,
+            frontend_path,
+            backend_path,
+            watch,
+            callbacks: Vec::new(),
+        })
+    }
+
+    pub fn device(&self) -> &XenbusDevice {
+        &self.device
+    }
+
+    pub fn role(&self) -> XenbusRole {
+        self.role
+    }
+
+    pub fn path(&self) -> &XsPath {
+        match self.role {
+            XenbusRole::Frontend => &self.frontend_path,
+            XenbusRole::Backend => &self.backend_path,
+        }
+    }
+
+    pub fn peer_path(&self) -> &XsPath {
+        match self.role {
+            XenbusRole::Frontend => &self.backend_path,
+            XenbusRole::Backend => &self.frontend_path,
+        }
+    }
+
+    pub fn state(&self) -> Result<XenbusState, std::io::Error> {
+        XenbusState::read(self.xsh, self.path())
+    }
+
+    pub fn set_state(&self, state: XenbusState) -> Result<(), std::io::Error> {
+        state.write(self.xsh, self.path())
+    }
+
+    /* Last state of the peer seen by process_event() */
+    pub fn peer_state(&self) -> XenbusState {
+        self.peer_state
+    }
+
+    /* `callback` gets the peer's previous and new states */
+    pub fn on_transition<F>(&mut self, callback: F)
+    where
+        F: FnMut(&XenbusDriver<'a>, XenbusState, XenbusState) -> Result<(), std::io::Error> + 'a,
+    {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /*
+     * Wait for the peer's state node to be written and run the callbacks if
+     * the state did change.  Returns the peer's current state.
+     */
+    pub fn process_event(&mut self) -> Result<XenbusState, std::io::Error> {
+        self.watch.read_watch()?;
+
+        let old = self.peer_state;
+        let new = XenbusState::read(self.xsh, self.peer_path())?;
+        if new == old {
+            return Ok(new);
+        }
+        self.peer_state = new;
+
+        /* Callbacks get to look at the driver, keep them aside meanwhile */
+        let mut callbacks = std::mem::take(&mut self.callbacks);
+        let res = callbacks
+            .iter_mut()
+            .try_for_each(|callback| callback(self, old, new));
+        callbacks.append(&mut self.callbacks);
+        self.callbacks = callbacks;
+
+        res.map(|_| new)
+    }
+
+    /* Process events until the peer reaches one of `states` */
+    pub fn wait_for_peer(&mut self, states: &[XenbusState]) -> Result<XenbusState, std::io::Error> {
+        while !states.contains(&self.peer_state) {
+            self.process_event()?;
+        }
+
+        Ok(self.peer_state)
+    }
+
+    /* Readable when process_event() won't block */
+    pub fn fileno(&self) -> Result<RawFd, std::io::Error> {
+        self.watch.fileno()
+    }
+
+    /* The toolstack sets "online" to 0 in the backend when the device is to go away */
+    pub fn online(&self) -> Result<bool, std::io::Error> {
+        match self.xsh.read_str(&self.backend_path.join("online")?) {
+            Ok(online) => Ok(online.trim() == "1"),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_online(&self, online: bool) -> Result<(), std::io::Error> {
+        self.xsh.write_str(
+            &self.backend_path.join("online")?,
+            if online { "1" } else { "0" },
+        )
+    }
+
+    /* Written by hotplug scripts, or the backend itself when it has none */
+    pub fn hotplug_status(&self) -> Result<Option<String>, std::io::Error> {
+        match self
+            .xsh
+            .read_str(&self.backend_path.join("hotplug-status")?)
+        {
+            Ok(status) => Ok(Some(status)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_hotplug_status(&self, status: &str) -> Result<(), std::io::Error> {
+        self.xsh
+            .write_str(&self.backend_path.join("hotplug-status")?, status)
+    }
+}
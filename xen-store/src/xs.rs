@@ -11,359 +11,951 @@
 #![allow(clippy::type_complexity)]
 
 use std::{
-    collections::VecDeque,
-    ffi::CString,
+    collections::{HashMap, VecDeque},
     io::{Error, ErrorKind, Read, Write},
-    mem,
-    net::Shutdown,
-    os::unix::{io::AsRawFd, net::UnixStream},
-    sync::{Arc, Condvar, Mutex},
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
     thread,
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use nix::libc::iovec;
+use nix::{
+    errno::Errno,
+    libc::{E2BIG, EAGAIN, ECANCELED},
+    poll::{poll, PollFd, PollFlags},
+};
 use vmm_sys_util::eventfd::{EventFd, EFD_SEMAPHORE};
-use xen_bindings::bindings::xs_watch_type;
 
-use crate::types::*;
+use crate::{codec::*, perms::*, transport::*, types::*};
 
+pub const XS_CONTROL: u32 = 0;
 pub const XS_DIRECTORY: u32 = 1;
 pub const XS_READ: u32 = 2;
+pub const XS_GET_PERMS: u32 = 3;
 pub const XS_WATCH: u32 = 4;
+pub const XS_UNWATCH: u32 = 5;
+pub const XS_TRANSACTION_START: u32 = 6;
+pub const XS_TRANSACTION_END: u32 = 7;
+pub const XS_INTRODUCE: u32 = 8;
+pub const XS_RELEASE: u32 = 9;
+pub const XS_GET_DOMAIN_PATH: u32 = 10;
 pub const XS_WRITE: u32 = 11;
+pub const XS_MKDIR: u32 = 12;
+pub const XS_RM: u32 = 13;
+pub const XS_SET_PERMS: u32 = 14;
 pub const XS_WATCH_EVENT: u32 = 15;
+pub const XS_ERROR: u32 = 16;
+pub const XS_IS_DOMAIN_INTRODUCED: u32 = 17;
+pub const XS_RESUME: u32 = 18;
+pub const XS_SET_TARGET: u32 = 19;
+pub const XS_RESET_WATCHES: u32 = 21;
+pub const XS_DIRECTORY_PART: u32 = 22;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    pub path: String,
+    pub token: String,
+}
+
+/* Per watch queue of events, filled by thread_function() */
+struct WatchQueue {
+    /* Needed to register the watch again after a reconnection */
+    path: String,
+    events: Mutex<VecDeque<WatchEvent>>,
+    cvar: Condvar,
+    eventfd: EventFd,
+    /* Removed by reset_watches(), the Watch has nothing to wait for */
+    reset: AtomicBool,
+    /* eventfd raised by wake(), with no event behind it */
+    woken: AtomicBool,
+}
+
+impl WatchQueue {
+    fn push(&self, event: WatchEvent) {
+        let mut events = self.events.lock().unwrap();
+
+        /* Increment evenfd counter to be consumed in Watch::read_watch() */
+        let _ = self
+            .eventfd
+            .write(1)
+            .map_err(|e| println!("WatchQueue::push: error: {}", e));
+
+        events.push_back(event);
+        self.cvar.notify_one();
+    }
 
-fn queue_message(
-    condvar: &Arc<(
-        Mutex<VecDeque<Result<XenStoreMessage, std::io::Error>>>,
+    /* Have poll() on fileno() return so that the next read reconnects */
+    fn wake(&self) {
+        let _events = self.events.lock().unwrap();
+
+        if !self.woken.swap(true, Ordering::SeqCst) {
+            let _ = self.eventfd.write(1);
+        }
+        self.cvar.notify_all();
+    }
+
+    fn cancel(&self) {
+        let _events = self.events.lock().unwrap();
+
+        self.reset.store(true, Ordering::SeqCst);
+        /* Left set for good, poll() on fileno() returns and the read errors out */
+        let _ = self.eventfd.write(1);
+        self.cvar.notify_all();
+    }
+}
+
+fn route_reply(
+    condvar: &(
+        Mutex<HashMap<u32, Option<Result<XsPacket, std::io::Error>>>>,
         Condvar,
-    )>,
-    eventfd: Option<EventFd>,
-    message: Result<XenStoreMessage, std::io::Error>,
+    ),
+    req_id: u32,
+    message: Result<XsPacket, std::io::Error>,
 ) {
-    let (lock, cvar) = &**condvar;
+    let (lock, cvar) = condvar;
 
-    let mut queue = lock.lock().unwrap();
+    let mut pending = lock.lock().unwrap();
 
-    if let Some(eventfd) = eventfd {
-        /* Increment evenfd counter to be consumed in read_watch() */
-        let _ = eventfd
-            .write(1)
-            .map_err(|e| println!("queue_message: error: {}", e));
+    /* Replies nobody is waiting for are dropped */
+    if let Some(slot) = pending.get_mut(&req_id) {
+        *slot = Some(message);
+        /* Several threads may be waiting, each for its own req_id */
+        cvar.notify_all();
     }
+}
+
+/* Wait for `rx_socket` to be readable, returns false when asked to stop */
+fn wait_readable(
+    rx_socket: &XenStoreStream,
+    stop_eventfd: &EventFd,
+) -> Result<bool, std::io::Error> {
+    let mut poll_fds = [
+        PollFd::new(rx_socket.as_raw_fd(), PollFlags::POLLIN),
+        PollFd::new(stop_eventfd.as_raw_fd(), PollFlags::POLLIN),
+    ];
 
-    queue.push_back(message);
-    cvar.notify_one();
+    loop {
+        match poll(&mut poll_fds, -1) {
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(Error::from(e)),
+        }
+    }
+
+    Ok(!poll_fds[1]
+        .revents()
+        .is_some_and(|revents| revents.contains(PollFlags::POLLIN)))
 }
 
 fn thread_function(
-    mut rx_socket: UnixStream,
-    tx_eventfd: EventFd,
-    reply_condvar: Arc<(
-        Mutex<VecDeque<Result<XenStoreMessage, std::io::Error>>>,
-        Condvar,
-    )>,
-    watch_condvar: Arc<(
-        Mutex<VecDeque<Result<XenStoreMessage, std::io::Error>>>,
-        Condvar,
-    )>,
+    mut rx_socket: XenStoreStream,
+    stop_eventfd: EventFd,
+    connection: Arc<XenStoreConnection>,
 ) -> Result<(), std::io::Error> {
-    loop {
-        let mut xen_socket_reply_msg = XenSocketMessage::default();
-        let mut buffer: Vec<u8> = vec![0];
-        let mut condvar = reply_condvar.clone();
-        let mut eventfd: Option<EventFd> = None;
+    /*
+     * A character device can't be shut down the way a socket can, an
+     * eventfd is used to tell the thread it's time to stop instead.
+     */
+    while wait_readable(&rx_socket, &stop_eventfd)? {
+        let mut header = [0; XS_HEADER_SIZE];
+
+        rx_socket.read_exact(&mut header)?;
+        /* Past a bad header there is no telling where the next one starts */
+        let header = XsHeader::decode(&header)?;
+
+        let mut payload: Vec<u8> = vec![0; header.len as usize];
+        rx_socket.read_exact(payload.as_mut_slice())?;
+
+        let packet = XsPacket::from_parts(header, payload)?;
+
+        /*
+         * Watch events aren't a reply to anything, they go to the queue of the
+         * watch their token belongs to.  Events nobody is listening to, i.e
+         * that arrive after a watch was dropped, are discarded.
+         */
+        if packet.r#type == XS_WATCH_EVENT {
+            if let Ok(XsReply::WatchEvent(event)) = XsReply::from_packet(XS_WATCH_EVENT, &packet) {
+                let queue = connection
+                    .watches
+                    .lock()
+                    .unwrap()
+                    .get(&event.token)
+                    .cloned();
+                if let Some(queue) = queue {
+                    queue.push(event);
+                }
+            }
+            continue;
+        }
+
+        route_reply(&connection.reply_condvar, packet.req_id, Ok(packet));
+    }
+
+    Ok(())
+}
+
+/* State shared between a XenStoreHandle, its reader thread and its watches */
+struct XenStoreConnection {
+    reply_condvar: (
+        Mutex<HashMap<u32, Option<Result<XsPacket, std::io::Error>>>>,
+        Condvar,
+    ),
+    watches: Mutex<HashMap<String, Arc<WatchQueue>>>,
+    next_req_id: AtomicU32,
+    tx_socket: Mutex<XenStoreStream>,
+    transport: XenStoreTransport,
+    /* Only changed with the reply_condvar mutex held */
+    connected: AtomicBool,
+    handler: Mutex<Option<JoinHandle<Result<(), std::io::Error>>>>,
+    stop_eventfd: EventFd,
+    /* How long to wait for a reply, forever if None */
+    request_timeout: Mutex<Option<Duration>>,
+}
+
+impl XenStoreConnection {
+    fn spawn_reader(
+        self: &Arc<Self>,
+        rx_socket: XenStoreStream,
+    ) -> Result<JoinHandle<Result<(), std::io::Error>>, std::io::Error> {
+        let stop_eventfd = self.stop_eventfd.try_clone()?;
+        let connection = Arc::clone(self);
+
+        Ok(thread::spawn(move || {
+            let res = thread_function(rx_socket, stop_eventfd, Arc::clone(&connection));
+            connection.disconnected();
+            res
+        }))
+    }
+
+    /*
+     * Called when the reader thread exits.  Requests waiting for a reply
+     * would never get one, fail them, and have read_watch() callers, blocked
+     * or polling fileno(), notice the connection is gone.
+     */
+    fn disconnected(&self) {
+        let (lock, cvar) = &self.reply_condvar;
 
         {
-            // SAFETY: `xen_socket_reply_msg` is `XenSocketMessage` bytes sized.
-            let xen_socket_reply_msg_slice: &mut [u8] = unsafe {
-                std::slice::from_raw_parts_mut(
-                    std::ptr::addr_of_mut!(xen_socket_reply_msg).cast(),
-                    mem::size_of::<XenSocketMessage>(),
-                )
-            };
+            let mut pending = lock.lock().unwrap();
 
-            rx_socket.read_exact(xen_socket_reply_msg_slice)?;
+            self.connected.store(false, Ordering::Release);
+            for slot in pending.values_mut().filter(|slot| slot.is_none()) {
+                *slot = Some(Err(Error::from(ErrorKind::ConnectionAborted)));
+            }
+            cvar.notify_all();
         }
 
-        if xen_socket_reply_msg.r#type == XS_READ && xen_socket_reply_msg.len == 0 {
-            queue_message(
-                &condvar,
-                eventfd,
-                Ok(XenStoreMessage {
-                    r#type: xen_socket_reply_msg.r#type,
-                    body: "".to_string(),
-                }),
-            );
-            continue;
+        for queue in self.watches.lock().unwrap().values() {
+            queue.wake();
         }
+    }
 
-        buffer.resize(xen_socket_reply_msg.len as usize, 0);
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
 
-        rx_socket.read_exact(buffer.as_mut_slice())?;
+    /*
+     * Open a new connection using the same transport, i.e after xenstored was
+     * restarted.  Watches are registered again, which makes each of them
+     * fire once as it does when first set.
+     */
+    fn reconnect(self: &Arc<Self>) -> Result<(), std::io::Error> {
+        let mut handler = self.handler.lock().unwrap();
+
+        /* Somebody else got there first */
+        if self.is_connected() {
+            return Ok(());
+        }
+
+        /* The reader thread marks the connection dead right before returning */
+        if let Some(handler) = handler.take() {
+            let _ = handler.join();
+        }
+
+        let tx_socket = self.transport.connect()?;
+        let rx_socket = tx_socket.try_clone()?;
+        *self.tx_socket.lock().unwrap() = tx_socket;
 
-        if xen_socket_reply_msg.r#type != XS_READ
-            && xen_socket_reply_msg.r#type != XS_WRITE
-            && xen_socket_reply_msg.r#type != XS_WATCH
-            && xen_socket_reply_msg.r#type != XS_WATCH_EVENT
-            && xen_socket_reply_msg.r#type != XS_DIRECTORY
         {
-            queue_message(
-                &condvar,
-                eventfd,
-                Err(Error::new(ErrorKind::Other, "Xen Store transaction error")),
-            );
-            continue;
+            let _pending = self.reply_condvar.0.lock().unwrap();
+            self.connected.store(true, Ordering::Release);
+        }
+        *handler = Some(self.spawn_reader(rx_socket)?);
+
+        let watches: Vec<(String, String)> = self
+            .watches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(token, queue)| (queue.path.clone(), token.clone()))
+            .collect();
+        for (path, token) in watches {
+            /* Not xs_request(), we're already reconnecting */
+            self.xs_transfer(XBT_NULL, &XsRequest::Watch { path, token })?;
         }
 
-        if xen_socket_reply_msg.r#type == XS_WATCH_EVENT {
-            condvar = watch_condvar.clone();
-            eventfd = Some(tx_eventfd.try_clone()?);
+        Ok(())
+    }
+
+    fn xs_request(
+        self: &Arc<Self>,
+        tx_id: u32,
+        request: &XsRequest,
+    ) -> Result<XsReply, std::io::Error> {
+        if !self.is_connected() {
+            self.reconnect()?;
         }
 
-        match String::from_utf8(buffer) {
-            Ok(result) => {
-                if result.len() != xen_socket_reply_msg.len as usize {
-                    queue_message(&condvar, eventfd, Err(Error::from(ErrorKind::InvalidData)));
-                    continue;
-                }
+        self.xs_transfer(tx_id, request)
+    }
 
-                queue_message(
-                    &condvar,
-                    eventfd,
-                    Ok(XenStoreMessage {
-                        r#type: xen_socket_reply_msg.r#type,
-                        body: result,
-                    }),
-                );
-            }
-            Err(e) => {
-                queue_message(&condvar, eventfd, Err(Error::new(ErrorKind::Other, e)));
+    /* XS_ERROR replies are turned into an Err */
+    fn xs_transfer(&self, tx_id: u32, request: &XsRequest) -> Result<XsReply, std::io::Error> {
+        let req_id = self.next_req_id.fetch_add(1, Ordering::Relaxed);
+        let packet = request.to_packet(req_id, tx_id)?;
+        let (lock, cvar) = &self.reply_condvar;
+
+        /* Register before sending so that the reply can't be dropped */
+        {
+            let mut pending = lock.lock().unwrap();
+            if !self.is_connected() {
+                return Err(Error::from(ErrorKind::NotConnected));
             }
-        };
+            pending.insert(req_id, None);
+        }
+
+        if let Err(e) = self.xs_send(&packet) {
+            lock.lock().unwrap().remove(&req_id);
+            return Err(e);
+        }
+
+        let deadline = self
+            .request_timeout
+            .lock()
+            .unwrap()
+            .map(|timeout| Instant::now() + timeout);
+        let mut pending = lock.lock().unwrap();
+        while pending.get(&req_id).unwrap().is_none() {
+            pending = match deadline {
+                None => cvar.wait(pending).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        /* The reply, if it ever comes, is dropped by route_reply() */
+                        pending.remove(&req_id);
+                        return Err(Error::from(ErrorKind::TimedOut));
+                    }
+                    cvar.wait_timeout(pending, deadline - now).unwrap().0
+                }
+            };
+        }
+
+        let reply = pending.remove(&req_id).unwrap().unwrap()?;
+
+        XsReply::from_packet(request.r#type(), &reply)?.into_result()
+    }
+
+    fn xs_send(&self, packet: &XsPacket) -> Result<(), std::io::Error> {
+        /*
+         * The mutex is only held while the request goes out, so that it isn't
+         * interleaved with another thread's.  Replies are matched to their
+         * request using req_id.
+         */
+        self.tx_socket.lock().unwrap().write_all(&packet.encode())
     }
 }
 
+fn unexpected_reply() -> Error {
+    Error::from(ErrorKind::InvalidData)
+}
+
+/*
+ * Requests made once the connection to xenstored is lost, i.e because it was
+ * restarted, open a new one.  Those waiting for a reply when that happens fail
+ * with ErrorKind::ConnectionAborted, as do transactions that were under way.
+ */
 pub struct XenStoreHandle {
-    handler: Option<JoinHandle<Result<(), std::io::Error>>>,
-    reply_condvar: Arc<(
-        Mutex<VecDeque<Result<XenStoreMessage, std::io::Error>>>,
-        Condvar,
-    )>,
-    watch_condvar: Arc<(
-        Mutex<VecDeque<Result<XenStoreMessage, std::io::Error>>>,
-        Condvar,
-    )>,
-    tx_socket: Mutex<UnixStream>,
-    rx_eventfd: EventFd,
+    connection: Arc<XenStoreConnection>,
 }
 
 impl XenStoreHandle {
+    /*
+     * Connect to the xenstored socket, or to the xenbus device when there is
+     * no xenstored in this domain.  XENSTORED_PATH takes precedence if set.
+     */
     pub fn new() -> Result<Self, std::io::Error> {
-        let tx_socket = UnixStream::connect(XENSTORED_SOCKET)?;
+        let (transport, tx_socket) = XenStoreTransport::connect_any()?;
+
+        Self::from_stream(transport, tx_socket)
+    }
+
+    pub fn with_transport(transport: &XenStoreTransport) -> Result<Self, std::io::Error> {
+        let tx_socket = transport.connect()?;
+
+        Self::from_stream(transport.clone(), tx_socket)
+    }
+
+    fn from_stream(
+        transport: XenStoreTransport,
+        tx_socket: XenStoreStream,
+    ) -> Result<Self, std::io::Error> {
         let rx_socket = tx_socket.try_clone()?;
-        let tx_eventfd = EventFd::new(EFD_SEMAPHORE)?;
-        let rx_eventfd = tx_eventfd.try_clone()?;
-        let reply_condvar = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
-        let reply_condvar_cloned = Arc::clone(&reply_condvar);
-        let watch_condvar = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
-        let watch_condvar_cloned = Arc::clone(&watch_condvar);
-
-        let handler = thread::spawn(|| {
-            thread_function(
-                rx_socket,
-                tx_eventfd,
-                reply_condvar_cloned,
-                watch_condvar_cloned,
-            )
+        let connection = Arc::new(XenStoreConnection {
+            reply_condvar: (Mutex::new(HashMap::new()), Condvar::new()),
+            watches: Mutex::new(HashMap::new()),
+            next_req_id: AtomicU32::new(0),
+            tx_socket: Mutex::new(tx_socket),
+            transport,
+            connected: AtomicBool::new(true),
+            handler: Mutex::new(None),
+            stop_eventfd: EventFd::new(0)?,
+            request_timeout: Mutex::new(None),
         });
 
-        Ok(XenStoreHandle {
-            handler: Some(handler),
-            reply_condvar,
-            watch_condvar,
-            tx_socket: Mutex::new(tx_socket),
-            rx_eventfd,
-        })
+        *connection.handler.lock().unwrap() = Some(connection.spawn_reader(rx_socket)?);
+
+        Ok(XenStoreHandle { connection })
+    }
+
+    /* Open a new connection now rather than on the next request */
+    pub fn reconnect(&self) -> Result<(), std::io::Error> {
+        self.connection.reconnect()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_connected()
+    }
+
+    /*
+     * Requests that don't get a reply within `timeout` fail with
+     * ErrorKind::TimedOut.  There is no timeout by default.
+     */
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        *self.connection.request_timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        *self.connection.request_timeout.lock().unwrap()
+    }
+
+    pub fn transport(&self) -> &XenStoreTransport {
+        &self.connection.transport
     }
 
-    fn xs_transaction(
+    fn xs_request(&self, tx_id: u32, request: XsRequest) -> Result<XsReply, std::io::Error> {
+        self.connection.xs_request(tx_id, &request)
+    }
+
+    fn do_read(&self, tx_id: u32, path: &str) -> Result<Vec<u8>, std::io::Error> {
+        let path = String::from(path);
+
+        match self.xs_request(tx_id, XsRequest::Read { path })? {
+            XsReply::Value(value) => Ok(value),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    fn do_write(&self, tx_id: u32, path: &str, val: &[u8]) -> Result<(), std::io::Error> {
+        let (path, value) = (String::from(path), Vec::from(val));
+
+        self.xs_request(tx_id, XsRequest::Write { path, value })
+            .map(|_| ())
+    }
+
+    fn do_mkdir(&self, tx_id: u32, path: &str) -> Result<(), std::io::Error> {
+        let path = String::from(path);
+
+        self.xs_request(tx_id, XsRequest::Mkdir { path })
+            .map(|_| ())
+    }
+
+    fn do_rm(&self, tx_id: u32, path: &str) -> Result<(), std::io::Error> {
+        let path = String::from(path);
+
+        self.xs_request(tx_id, XsRequest::Rm { path }).map(|_| ())
+    }
+
+    fn do_directory(&self, tx_id: u32, path: &str) -> Result<Vec<String>, std::io::Error> {
+        let request = XsRequest::Directory {
+            path: String::from(path),
+        };
+
+        match self.xs_request(tx_id, request) {
+            Ok(XsReply::Directory(children)) => Ok(children),
+            Ok(_) => Err(unexpected_reply()),
+            /* Too many children to fit in a single reply, go for chunks */
+            Err(e) if e.raw_os_error() == Some(E2BIG) => self.do_directory_part(tx_id, path),
+            Err(e) => Err(e),
+        }
+    }
+
+    /* See XsDirectoryParts */
+    fn do_directory_part(&self, tx_id: u32, path: &str) -> Result<Vec<String>, std::io::Error> {
+        let mut parts = XsDirectoryParts::new(path);
+
+        loop {
+            let reply = self.xs_request(tx_id, parts.request())?;
+            if let Some(children) = parts.push(reply)? {
+                return Ok(children);
+            }
+        }
+    }
+
+    fn do_get_perms(&self, tx_id: u32, path: &str) -> Result<Vec<XsPermission>, std::io::Error> {
+        let path = String::from(path);
+
+        match self.xs_request(tx_id, XsRequest::GetPerms { path })? {
+            XsReply::Perms(perms) => Ok(perms),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    fn do_set_perms(
         &self,
-        r#type: u32,
-        iovec_buffers: &mut Vec<iovec>,
-    ) -> Result<String, std::io::Error> {
-        let mut xen_socket_msg = XenSocketMessage::new(r#type, iovec_buffers)?;
-        let (lock, cvar) = &*self.reply_condvar;
+        tx_id: u32,
+        path: &str,
+        perms: &[XsPermission],
+    ) -> Result<(), std::io::Error> {
+        let (path, perms) = (String::from(path), Vec::from(perms));
+
+        self.xs_request(tx_id, XsRequest::SetPerms { path, perms })
+            .map(|_| ())
+    }
+
+    fn do_grant_read_access(
+        &self,
+        tx_id: u32,
+        path: &str,
+        domid: u32,
+    ) -> Result<(), std::io::Error> {
+        let mut perms = self.do_get_perms(tx_id, path)?;
+
+        xs_grant(&mut perms, domid, XsAccess::Read);
+        self.do_set_perms(tx_id, path, &perms)?;
+
+        for child in self.do_directory(tx_id, path)? {
+            self.do_grant_read_access(tx_id, &format!("{}/{}", path, child), domid)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_bytes(&self, path: &str) -> Result<Vec<u8>, std::io::Error> {
+        self.do_read(XBT_NULL, path)
+    }
+
+    pub fn write_bytes(&self, path: &str, val: &[u8]) -> Result<(), std::io::Error> {
+        self.do_write(XBT_NULL, path, val)
+    }
+
+    pub fn read_str(&self, path: &str) -> Result<String, std::io::Error> {
+        self.read_bytes(path).and_then(body_to_string)
+    }
+
+    pub fn write_str(&self, path: &str, val: &str) -> Result<(), std::io::Error> {
+        self.write_bytes(path, val.as_bytes())
+    }
+
+    pub fn mkdir(&self, path: &str) -> Result<(), std::io::Error> {
+        self.do_mkdir(XBT_NULL, path)
+    }
+
+    pub fn rm(&self, path: &str) -> Result<(), std::io::Error> {
+        self.do_rm(XBT_NULL, path)
+    }
+
+    pub fn get_perms(&self, path: &str) -> Result<Vec<XsPermission>, std::io::Error> {
+        self.do_get_perms(XBT_NULL, path)
+    }
+
+    pub fn set_perms(&self, path: &str, perms: &[XsPermission]) -> Result<(), std::io::Error> {
+        self.do_set_perms(XBT_NULL, path, perms)
+    }
+
+    /*
+     * Let `domid` read `path` and everything below it, i.e a frontend its
+     * backend's nodes.  Other entries of the permission lists are kept and
+     * the whole subtree is updated in a single transaction.
+     */
+    pub fn grant_read_access(&self, path: &str, domid: u32) -> Result<(), std::io::Error> {
+        self.with_transaction(|transaction| {
+            self.do_grant_read_access(transaction.id(), path, domid)
+        })
+    }
+
+    /*
+     * Events for `path` are delivered to the returned Watch, and only to it,
+     * until it is dropped.  Tokens have to be unique for a given handle.
+     */
+    pub fn create_watch(&self, path: &str, token: &str) -> Result<Watch, std::io::Error> {
+        let queue = Arc::new(WatchQueue {
+            path: String::from(path),
+            events: Mutex::new(VecDeque::new()),
+            cvar: Condvar::new(),
+            eventfd: EventFd::new(EFD_SEMAPHORE)?,
+            reset: AtomicBool::new(false),
+            woken: AtomicBool::new(false),
+        });
 
-        let mut tx_socket = self.tx_socket.lock().unwrap();
         {
-            // SAFETY: `xen_socket_msg` is `XenSocketMessage` bytes sized.
-            let xen_socket_msg_slice: &[u8] = unsafe {
-                std::slice::from_raw_parts(
-                    std::ptr::addr_of_mut!(xen_socket_msg).cast(),
-                    mem::size_of::<XenSocketMessage>(),
-                )
-            };
+            let mut watches = self.connection.watches.lock().unwrap();
+            if watches.contains_key(token) {
+                return Err(Error::from(ErrorKind::AlreadyExists));
+            }
 
-            /*
-             * Grabbing the mutex guarantees there will only be
-             * one active transcation at a time.
-             */
-            tx_socket.write_all(xen_socket_msg_slice)?;
+            /* xenstored fires a first event as soon as the watch is set */
+            watches.insert(String::from(token), Arc::clone(&queue));
         }
 
-        // SAFETY: tx_socket is a valid file descriptor and the pointer/length we pass
-        // are valid allocated values.
-        let ret = unsafe {
-            libc::writev(
-                tx_socket.as_raw_fd(),
-                iovec_buffers.as_ptr(),
-                iovec_buffers.len() as i32,
-            )
+        let request = XsRequest::Watch {
+            path: String::from(path),
+            token: String::from(token),
         };
+        if let Err(e) = self.xs_request(XBT_NULL, request) {
+            self.connection.watches.lock().unwrap().remove(token);
+            return Err(e);
+        }
+
+        Ok(Watch {
+            connection: Arc::downgrade(&self.connection),
+            path: String::from(path),
+            token: String::from(token),
+            queue,
+        })
+    }
 
-        if ret < 0 {
-            return Err(Error::last_os_error());
+    /*
+     * Remove all the watches of the connection.  Events already received are
+     * still delivered, after that the Watches return ECANCELED.
+     */
+    pub fn reset_watches(&self) -> Result<(), std::io::Error> {
+        self.xs_request(XBT_NULL, XsRequest::ResetWatches)?;
+
+        let watches = std::mem::take(&mut *self.connection.watches.lock().unwrap());
+        for queue in watches.values() {
+            queue.cancel();
+        }
+
+        Ok(())
+    }
+
+    pub fn get_domain_path(&self, domid: u32) -> Result<String, std::io::Error> {
+        match self.xs_request(XBT_NULL, XsRequest::GetDomainPath { domid })? {
+            XsReply::DomainPath(path) => Ok(path),
+            _ => Err(unexpected_reply()),
         }
+    }
+
+    pub fn introduce(&self, domid: u32, mfn: u64, evtchn: u32) -> Result<(), std::io::Error> {
+        let request = XsRequest::Introduce {
+            domid,
+            mfn,
+            port: evtchn,
+        };
+
+        self.xs_request(XBT_NULL, request).map(|_| ())
+    }
 
-        let mut reply_vec = lock.lock().unwrap();
-        while reply_vec.is_empty() {
-            reply_vec = cvar.wait(reply_vec).unwrap();
+    pub fn release(&self, domid: u32) -> Result<(), std::io::Error> {
+        self.xs_request(XBT_NULL, XsRequest::Release { domid })
+            .map(|_| ())
+    }
+
+    pub fn is_domain_introduced(&self, domid: u32) -> Result<bool, std::io::Error> {
+        match self.xs_request(XBT_NULL, XsRequest::IsDomainIntroduced { domid })? {
+            XsReply::IsDomainIntroduced(introduced) => Ok(introduced),
+            _ => Err(unexpected_reply()),
         }
+    }
 
-        match reply_vec.pop_front() {
-            Some(result) => match result {
-                Ok(xsm) => {
-                    if xsm.r#type != r#type {
-                        return Err(Error::from(ErrorKind::InvalidData));
+    pub fn set_target(&self, domid: u32, target: u32) -> Result<(), std::io::Error> {
+        self.xs_request(XBT_NULL, XsRequest::SetTarget { domid, target })
+            .map(|_| ())
+    }
+
+    pub fn resume(&self, domid: u32) -> Result<(), std::io::Error> {
+        self.xs_request(XBT_NULL, XsRequest::Resume { domid })
+            .map(|_| ())
+    }
+
+    /* Send a xenstored specific command, e.g "log" or "quota" */
+    pub fn control(&self, cmd: &str, args: &[&str]) -> Result<String, std::io::Error> {
+        let mut c_args = vec![String::from(cmd)];
+        c_args.extend(args.iter().map(|arg| String::from(*arg)));
+
+        match self.xs_request(XBT_NULL, XsRequest::Control { args: c_args })? {
+            XsReply::Control(res) => Ok(res),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    pub fn directory(&self, path: &str) -> Result<Vec<String>, std::io::Error> {
+        self.do_directory(XBT_NULL, path)
+    }
+
+    /* For nodes whose children are IDs, i.e domains or devices */
+    pub fn directory_ids(&self, path: &str) -> Result<Vec<u32>, std::io::Error> {
+        self.directory(path)?
+            .iter()
+            .map(|v| {
+                v.parse::<u32>().map_err(|err| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Could not parse `{:?}` as `u32`: {err}", v),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    pub fn transaction_start(&self) -> Result<XenStoreTransaction<'_>, std::io::Error> {
+        let tx_id = match self.xs_request(XBT_NULL, XsRequest::TransactionStart)? {
+            XsReply::TransactionStarted(tx_id) => tx_id,
+            _ => return Err(unexpected_reply()),
+        };
+
+        Ok(XenStoreTransaction {
+            xsh: self,
+            tx_id,
+            finished: false,
+        })
+    }
+
+    /*
+     * Run `f` inside a transaction and commit it.  The whole sequence is
+     * started over for as long as xenstored reports a conflict (EAGAIN),
+     * which means `f` may be called more than once.
+     */
+    pub fn with_transaction<T, F>(&self, mut f: F) -> Result<T, std::io::Error>
+    where
+        F: FnMut(&XenStoreTransaction) -> Result<T, std::io::Error>,
+    {
+        loop {
+            let transaction = self.transaction_start()?;
+
+            let value = match f(&transaction) {
+                Ok(value) => value,
+                Err(e) => {
+                    let _ = transaction.abort();
+                    if e.raw_os_error() == Some(EAGAIN) {
+                        continue;
                     }
-                    Ok(xsm.body)
+                    return Err(e);
                 }
-                Err(e) => Err(e),
-            },
-            None => Err(Error::new(ErrorKind::Other, "Xen Store transaction error")),
+            };
+
+            match transaction.commit() {
+                Ok(()) => return Ok(value),
+                Err(e) if e.raw_os_error() == Some(EAGAIN) => continue,
+                Err(e) => return Err(e),
+            }
         }
     }
+}
 
-    pub fn read_str(&self, path: &str) -> Result<String, std::io::Error> {
-        let c_path = CString::new(path)?;
-        let mut iovec_buffers = vec![iovec {
-            iov_base: c_path.as_ptr() as *mut _,
-            iov_len: path.len() + 1,
-        }];
+pub struct XenStoreTransaction<'a> {
+    xsh: &'a XenStoreHandle,
+    tx_id: u32,
+    finished: bool,
+}
+
+impl XenStoreTransaction<'_> {
+    fn end(&mut self, commit: bool) -> Result<(), std::io::Error> {
+        /* Whatever the outcome, the transaction is gone on the xenstored side */
+        self.finished = true;
+        self.xsh
+            .xs_request(self.tx_id, XsRequest::TransactionEnd { commit })
+            .map(|_| ())
+    }
+
+    pub fn id(&self) -> u32 {
+        self.tx_id
+    }
+
+    pub fn read_bytes(&self, path: &str) -> Result<Vec<u8>, std::io::Error> {
+        self.xsh.do_read(self.tx_id, path)
+    }
 
-        self.xs_transaction(XS_READ, &mut iovec_buffers)
+    pub fn write_bytes(&self, path: &str, val: &[u8]) -> Result<(), std::io::Error> {
+        self.xsh.do_write(self.tx_id, path, val)
+    }
+
+    pub fn read_str(&self, path: &str) -> Result<String, std::io::Error> {
+        self.read_bytes(path).and_then(body_to_string)
     }
 
     pub fn write_str(&self, path: &str, val: &str) -> Result<(), std::io::Error> {
-        let cpath = CString::new(path)?;
-        let cval = CString::new(val)?;
-        let mut iovec_buffers = vec![
-            iovec {
-                iov_base: cpath.as_ptr() as *mut _,
-                iov_len: path.len() + 1,
-            },
-            iovec {
-                iov_base: cval.as_ptr() as *mut _,
-                iov_len: val.len(),
-            },
-        ];
-
-        self.xs_transaction(XS_WRITE, &mut iovec_buffers)
-            .map(|_| ())
+        self.write_bytes(path, val.as_bytes())
     }
 
-    pub fn create_watch(&self, path: &str, token: &str) -> Result<(), std::io::Error> {
-        let cpath = CString::new(path)?;
-        let ctoken = CString::new(token)?;
-        let mut iovec_buffers = vec![
-            iovec {
-                iov_base: cpath.as_ptr() as *mut _,
-                iov_len: path.len() + 1,
-            },
-            iovec {
-                iov_base: ctoken.as_ptr() as *mut _,
-                iov_len: token.len() + 1,
-            },
-        ];
-
-        self.xs_transaction(XS_WATCH, &mut iovec_buffers)
-            .map(|_| ())
+    pub fn mkdir(&self, path: &str) -> Result<(), std::io::Error> {
+        self.xsh.do_mkdir(self.tx_id, path)
     }
 
-    pub fn read_watch(&self, index: xs_watch_type) -> Result<String, std::io::Error> {
-        let (lock, cvar) = &*self.watch_condvar;
+    pub fn rm(&self, path: &str) -> Result<(), std::io::Error> {
+        self.xsh.do_rm(self.tx_id, path)
+    }
 
-        let mut watch_vec = lock.lock().unwrap();
-        while watch_vec.is_empty() {
-            watch_vec = cvar.wait(watch_vec).unwrap();
+    pub fn get_perms(&self, path: &str) -> Result<Vec<XsPermission>, std::io::Error> {
+        self.xsh.do_get_perms(self.tx_id, path)
+    }
+
+    pub fn set_perms(&self, path: &str, perms: &[XsPermission]) -> Result<(), std::io::Error> {
+        self.xsh.do_set_perms(self.tx_id, path, perms)
+    }
+
+    pub fn grant_read_access(&self, path: &str, domid: u32) -> Result<(), std::io::Error> {
+        self.xsh.do_grant_read_access(self.tx_id, path, domid)
+    }
+
+    pub fn directory(&self, path: &str) -> Result<Vec<String>, std::io::Error> {
+        self.xsh.do_directory(self.tx_id, path)
+    }
+
+    /* Returns an EAGAIN error if the transaction conflicted with another one */
+    pub fn commit(mut self) -> Result<(), std::io::Error> {
+        self.end(true)
+    }
+
+    pub fn abort(mut self) -> Result<(), std::io::Error> {
+        self.end(false)
+    }
+}
+
+impl Drop for XenStoreTransaction<'_> {
+    fn drop(&mut self) {
+        /* Don't leave a dangling transaction behind if commit() or abort() weren't called */
+        if !self.finished {
+            let _ = self.end(false);
         }
+    }
+}
+
+pub struct Watch {
+    connection: Weak<XenStoreConnection>,
+    path: String,
+    token: String,
+    queue: Arc<WatchQueue>,
+}
 
-        /* Consume eventfd counter incremented in queue_message() */
-        let _ = self.rx_eventfd.read().unwrap();
+impl Watch {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 
-        match watch_vec.pop_front() {
-            Some(result) => match result {
-                Ok(mut xsm) => {
-                    if xsm.r#type != XS_WATCH_EVENT {
-                        return Err(Error::from(ErrorKind::InvalidData));
-                    }
+    pub fn token(&self) -> &str {
+        &self.token
+    }
 
-                    let body = xsm.body.as_mut_str();
-                    let v: Vec<&str> = body.split('\0').collect();
-                    if index as usize >= v.len() {
-                        return Err(Error::from(ErrorKind::InvalidInput));
-                    }
+    /*
+     * Wait for an event, for at most `timeout` if there is one.  If xenstored
+     * goes away meanwhile a new connection is opened, and an error is
+     * returned if that isn't possible.
+     */
+    fn next_event(&self, timeout: Option<Duration>) -> Result<Option<WatchEvent>, std::io::Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut events = self.queue.events.lock().unwrap();
+
+        loop {
+            if let Some(event) = events.pop_front() {
+                /* Consume eventfd counter incremented in WatchQueue::push() */
+                let _ = self.queue.eventfd.read().unwrap();
+                return Ok(Some(event));
+            }
+            if self.queue.reset.load(Ordering::SeqCst) {
+                return Err(Error::from_raw_os_error(ECANCELED));
+            }
+
+            let connection = self
+                .connection
+                .upgrade()
+                .ok_or_else(|| Error::from(ErrorKind::NotConnected))?;
+            if !connection.is_connected() {
+                drop(events);
+                connection.reconnect()?;
+                events = self.queue.events.lock().unwrap();
+                continue;
+            }
+            drop(connection);
 
-                    Ok(String::from(v[index as usize]))
+            /* Connected again, the wake up from disconnected() is over */
+            if self.queue.woken.swap(false, Ordering::SeqCst) {
+                let _ = self.queue.eventfd.read().unwrap();
+                continue;
+            }
+
+            events = match deadline {
+                None => self.queue.cvar.wait(events).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    self.queue
+                        .cvar
+                        .wait_timeout(events, deadline - now)
+                        .unwrap()
+                        .0
                 }
-                Err(e) => Err(e),
-            },
-            None => Err(Error::new(ErrorKind::Other, "Xen Store transaction error")),
+            };
         }
     }
 
+    /* Blocks until an event comes, see next_event() */
+    pub fn read_watch(&self) -> Result<WatchEvent, std::io::Error> {
+        self.next_event(None)?
+            .ok_or_else(|| Error::other("Xen Store watch error"))
+    }
+
+    /* Doesn't wait for events, Ok(None) if there is none queued */
+    pub fn try_read_watch(&self) -> Result<Option<WatchEvent>, std::io::Error> {
+        self.next_event(Some(Duration::ZERO))
+    }
+
+    /* As read_watch() but gives up with ErrorKind::TimedOut after `timeout` */
+    pub fn read_watch_timeout(&self, timeout: Duration) -> Result<WatchEvent, std::io::Error> {
+        self.next_event(Some(timeout))?
+            .ok_or_else(|| Error::from(ErrorKind::TimedOut))
+    }
+
+    /*
+     * Readable whenever read_watch() has an event to return, or has to
+     * connect again because xenstored went away.
+     */
     pub fn fileno(&self) -> Result<i32, std::io::Error> {
-        Ok(self.rx_eventfd.as_raw_fd())
-    }
-
-    pub fn directory(&self, path: &str) -> Result<Vec<i32>, std::io::Error> {
-        let c_path = CString::new(path)?;
-        let mut iovec_buffers = vec![iovec {
-            iov_base: c_path.as_ptr() as *mut _,
-            iov_len: path.len() + 1,
-        }];
-
-        match self.xs_transaction(XS_DIRECTORY, &mut iovec_buffers) {
-            Ok(res) => Ok(res
-                .as_str()
-                .split('\0')
-                .filter(|v| !v.is_empty())
-                .map(|v| {
-                    v.parse::<i32>()
-                        .map_err(|err| format!("Could not parse `{:?}` as `i32`: {err}", v))
-                        .unwrap()
-                })
-                .collect()),
-            Err(e) => Err(e),
-        }
+        Ok(self.queue.eventfd.as_raw_fd())
     }
 }
 
-impl Drop for XenStoreHandle {
+impl Drop for Watch {
     fn drop(&mut self) {
-        let tx_socket = self.tx_socket.lock().unwrap();
+        /* Already gone from xenstored, the token may belong to another Watch now */
+        if self.queue.reset.load(Ordering::SeqCst) {
+            return;
+        }
 
-        /*
-         * Calling shutdown() on the socket will cause the blocking
-         * rx_socket in thread_function() to return with an error
-         * condition, something that will automatically break the
-         * loop and cause the thread to stop.
-         */
-        let _ = tx_socket.shutdown(Shutdown::Both);
+        /* Nothing to unregister if the handle is already gone */
+        if let Some(connection) = self.connection.upgrade() {
+            connection.watches.lock().unwrap().remove(&self.token);
+            let request = XsRequest::Unwatch {
+                path: self.path.clone(),
+                token: self.token.clone(),
+            };
+            let _ = connection.xs_request(XBT_NULL, &request);
+        }
+    }
+}
 
-        /* Wait for it to stop */
-        let _ = self.handler.take().unwrap().join();
+impl Drop for XenStoreHandle {
+    fn drop(&mut self) {
+        /* Have thread_function() break out of its loop and wait for it to stop */
+        let _ = self.connection.stop_eventfd.write(1);
+        if let Some(handler) = self.connection.handler.lock().unwrap().take() {
+            let _ = handler.join();
+        }
     }
 }
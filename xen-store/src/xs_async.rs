@@ -0,0 +1,389 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+#![allow(clippy::type_complexity)]
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest, ReadBuf},
+    net::UnixStream,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{codec::*, transport::*, types::*, xs::*};
+
+type AsyncReader = Box<dyn AsyncRead + Send + Unpin>;
+type AsyncWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/*
+ * The xenbus device, made non-blocking.  Its poll() only ever reports
+ * POLLIN, writes are handled right away by the driver and never block.
+ */
+struct AsyncXenbusDevice(AsyncFd<File>);
+
+impl AsyncXenbusDevice {
+    fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        // SAFETY: `file` owns its descriptor, which is only closed when the
+        // AsyncFd is dropped, and as_raw_fd() always returns it
+        let file = unsafe { AsyncFd::register_with_interest(file, Interest::READABLE)? };
+
+        Ok(AsyncXenbusDevice(file))
+    }
+}
+
+impl AsyncRead for AsyncXenbusDevice {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+
+            match guard.try_io(|file| file.get_ref().read(buf.initialize_unfilled())) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                /* Readiness was cleared, wait for the next wake up */
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncXenbusDevice {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Poll::Ready(self.0.get_ref().write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct AsyncXenStoreInner {
+    tx_stream: tokio::sync::Mutex<AsyncWriter>,
+    next_req_id: AtomicU32,
+    /* Set to None once the reader task is gone */
+    pending: Mutex<Option<HashMap<u32, oneshot::Sender<XsPacket>>>>,
+    watches: Mutex<Option<HashMap<String, mpsc::UnboundedSender<WatchEvent>>>>,
+}
+
+impl AsyncXenStoreInner {
+    /* XS_ERROR replies are turned into an Err */
+    async fn xs_request(&self, request: &XsRequest) -> Result<XsReply, std::io::Error> {
+        let req_id = self.next_req_id.fetch_add(1, Ordering::Relaxed);
+        let packet = request.to_packet(req_id, XBT_NULL)?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        /* Register before sending so that the reply can't be dropped */
+        self.pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::NotConnected))?
+            .insert(req_id, reply_tx);
+
+        let sent = self
+            .tx_stream
+            .lock()
+            .await
+            .write_all(&packet.encode())
+            .await;
+
+        if let Err(e) = sent {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&req_id);
+            }
+            return Err(e);
+        }
+
+        /* The sender is dropped if the reader task stops */
+        let reply = reply_rx
+            .await
+            .map_err(|_| Error::from(ErrorKind::ConnectionAborted))?;
+
+        XsReply::from_packet(request.r#type(), &reply)?.into_result()
+    }
+}
+
+fn unexpected_reply() -> Error {
+    Error::from(ErrorKind::InvalidData)
+}
+
+/*
+ * Whether the reader task returns or gets aborted, requests waiting for a
+ * reply and watch streams have to be told the connection is gone.
+ */
+struct ReaderGuard(Arc<AsyncXenStoreInner>);
+
+impl Drop for ReaderGuard {
+    fn drop(&mut self) {
+        self.0.pending.lock().unwrap().take();
+        self.0.watches.lock().unwrap().take();
+    }
+}
+
+async fn reader_task(
+    mut rx_stream: AsyncReader,
+    inner: Arc<AsyncXenStoreInner>,
+) -> Result<(), std::io::Error> {
+    let _guard = ReaderGuard(Arc::clone(&inner));
+
+    loop {
+        let mut header = [0; XS_HEADER_SIZE];
+
+        rx_stream.read_exact(&mut header).await?;
+        let header = XsHeader::decode(&header)?;
+
+        let mut payload: Vec<u8> = vec![0; header.len as usize];
+        rx_stream.read_exact(payload.as_mut_slice()).await?;
+
+        let packet = XsPacket::from_parts(header, payload)?;
+
+        if packet.r#type == XS_WATCH_EVENT {
+            /* Events for a token nobody is listening to are dropped */
+            if let Ok(XsReply::WatchEvent(event)) = XsReply::from_packet(XS_WATCH_EVENT, &packet) {
+                if let Some(watch_tx) = inner
+                    .watches
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .and_then(|watches| watches.get(&event.token))
+                {
+                    let _ = watch_tx.send(event);
+                }
+            }
+            continue;
+        }
+
+        if let Some(reply_tx) = inner
+            .pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|pending| pending.remove(&packet.req_id))
+        {
+            let _ = reply_tx.send(packet);
+        }
+    }
+}
+
+/*
+ * Asynchronous flavour of XenStoreHandle.  Replies and watch events are read
+ * by a task spawned on the current tokio runtime, there is no reader thread.
+ */
+pub struct AsyncXenStoreHandle {
+    inner: Arc<AsyncXenStoreInner>,
+    reader: JoinHandle<Result<(), std::io::Error>>,
+}
+
+impl AsyncXenStoreHandle {
+    /* Tries the same transports as XenStoreHandle::new() */
+    pub async fn new() -> Result<Self, std::io::Error> {
+        let mut error = Error::from(ErrorKind::NotFound);
+
+        for transport in XenStoreTransport::candidates()? {
+            match Self::with_transport(&transport).await {
+                Ok(xsh) => return Ok(xsh),
+                Err(e) => error = e,
+            }
+        }
+
+        Err(error)
+    }
+
+    pub async fn with_transport(transport: &XenStoreTransport) -> Result<Self, std::io::Error> {
+        let (rx_stream, tx_stream): (AsyncReader, AsyncWriter) = match transport {
+            XenStoreTransport::Socket(path) => {
+                let (rx_stream, tx_stream) = UnixStream::connect(path).await?.into_split();
+                (Box::new(rx_stream), Box::new(tx_stream))
+            }
+            XenStoreTransport::Device(path) => {
+                let (rx_stream, tx_stream) = tokio::io::split(AsyncXenbusDevice::open(path)?);
+                (Box::new(rx_stream), Box::new(tx_stream))
+            }
+        };
+        let inner = Arc::new(AsyncXenStoreInner {
+            tx_stream: tokio::sync::Mutex::new(tx_stream),
+            next_req_id: AtomicU32::new(0),
+            pending: Mutex::new(Some(HashMap::new())),
+            watches: Mutex::new(Some(HashMap::new())),
+        });
+
+        let reader = tokio::spawn(reader_task(rx_stream, Arc::clone(&inner)));
+
+        Ok(AsyncXenStoreHandle { inner, reader })
+    }
+
+    pub async fn read_bytes(&self, path: &str) -> Result<Vec<u8>, std::io::Error> {
+        let path = String::from(path);
+
+        match self.inner.xs_request(&XsRequest::Read { path }).await? {
+            XsReply::Value(value) => Ok(value),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    pub async fn write_bytes(&self, path: &str, val: &[u8]) -> Result<(), std::io::Error> {
+        let (path, value) = (String::from(path), Vec::from(val));
+
+        self.inner
+            .xs_request(&XsRequest::Write { path, value })
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn read(&self, path: &str) -> Result<String, std::io::Error> {
+        self.read_bytes(path).await.and_then(body_to_string)
+    }
+
+    pub async fn write(&self, path: &str, val: &str) -> Result<(), std::io::Error> {
+        self.write_bytes(path, val.as_bytes()).await
+    }
+
+    pub async fn directory(&self, path: &str) -> Result<Vec<String>, std::io::Error> {
+        let request = XsRequest::Directory {
+            path: String::from(path),
+        };
+
+        match self.inner.xs_request(&request).await {
+            Ok(XsReply::Directory(children)) => Ok(children),
+            Ok(_) => Err(unexpected_reply()),
+            /* Too many children to fit in a single reply, go for chunks */
+            Err(e) if e.raw_os_error() == Some(libc::E2BIG) => self.directory_part(path).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    /* See XsDirectoryParts */
+    async fn directory_part(&self, path: &str) -> Result<Vec<String>, std::io::Error> {
+        let mut parts = XsDirectoryParts::new(path);
+
+        loop {
+            let reply = self.inner.xs_request(&parts.request()).await?;
+            if let Some(children) = parts.push(reply)? {
+                return Ok(children);
+            }
+        }
+    }
+
+    /*
+     * Only one stream can exist for a given token at a time.  As with the
+     * blocking API xenstored fires an event for `path` as soon as the watch
+     * is registered.
+     */
+    pub async fn watch(&self, path: &str, token: &str) -> Result<WatchStream, std::io::Error> {
+        let (watch_tx, watch_rx) = mpsc::unbounded_channel();
+
+        {
+            let mut watches = self.inner.watches.lock().unwrap();
+            let watches = watches
+                .as_mut()
+                .ok_or_else(|| Error::from(ErrorKind::NotConnected))?;
+            if watches.contains_key(token) {
+                return Err(Error::from(ErrorKind::AlreadyExists));
+            }
+            watches.insert(String::from(token), watch_tx);
+        }
+
+        let request = XsRequest::Watch {
+            path: String::from(path),
+            token: String::from(token),
+        };
+        if let Err(e) = self.inner.xs_request(&request).await {
+            if let Some(watches) = self.inner.watches.lock().unwrap().as_mut() {
+                watches.remove(token);
+            }
+            return Err(e);
+        }
+
+        Ok(WatchStream {
+            inner: Arc::clone(&self.inner),
+            path: String::from(path),
+            token: String::from(token),
+            watch_rx,
+        })
+    }
+}
+
+impl Drop for AsyncXenStoreHandle {
+    fn drop(&mut self) {
+        /* Outstanding requests and watch streams see the connection go away */
+        self.reader.abort();
+    }
+}
+
+pub struct WatchStream {
+    inner: Arc<AsyncXenStoreInner>,
+    path: String,
+    token: String,
+    watch_rx: mpsc::UnboundedReceiver<WatchEvent>,
+}
+
+impl Stream for WatchStream {
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WatchEvent>> {
+        self.watch_rx.poll_recv(cx)
+    }
+}
+
+impl Drop for WatchStream {
+    fn drop(&mut self) {
+        if let Some(watches) = self.inner.watches.lock().unwrap().as_mut() {
+            watches.remove(&self.token);
+        }
+
+        /* Drop can't wait, unregister the watch in the background if we can */
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let request = XsRequest::Unwatch {
+                path: self.path.clone(),
+                token: self.token.clone(),
+            };
+            let inner = Arc::clone(&self.inner);
+            runtime.spawn(async move { inner.xs_request(&request).await });
+        }
+    }
+}
@@ -0,0 +1,350 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    io::ErrorKind,
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    libc::{E2BIG, EACCES, EAGAIN, ECANCELED, ENOSPC},
+    poll::{poll, PollFd, PollFlags},
+};
+use xen_store::{MockXenStored, WatchEvent, XsAccess, XsPath, XsPermission};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn event(path: &str, token: &str) -> WatchEvent {
+    WatchEvent {
+        path: String::from(path),
+        token: String::from(token),
+    }
+}
+
+#[test]
+fn read_write_directory_rm() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    xsh.write_str("/test/a", "1").unwrap();
+    xsh.write_bytes("/test/b", b"\x00\xff").unwrap();
+    xsh.mkdir("/test/c").unwrap();
+
+    assert_eq!(xsh.read_str("/test/a").unwrap(), "1");
+    assert_eq!(xsh.read_bytes("/test/b").unwrap(), b"\x00\xff");
+    assert_eq!(xsh.read_str("/test/c").unwrap(), "");
+    assert_eq!(xsh.directory("/test").unwrap(), ["a", "b", "c"]);
+
+    xsh.rm("/test/a").unwrap();
+    assert_eq!(
+        xsh.read_str("/test/a").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(xsh.directory("/test").unwrap(), ["b", "c"]);
+
+    /* Everything below goes along */
+    xsh.rm("/test").unwrap();
+    assert_eq!(
+        xsh.directory("/test").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn directory_part() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    /* Far more than fits in a single XS_DIRECTORY reply */
+    let mut children: Vec<String> = (0..1000).map(|i| format!("child-{:04}", i)).collect();
+    xsh.with_transaction(|tx| {
+        for child in children.iter() {
+            tx.write_str(&format!("/big/{}", child), "")?;
+        }
+        Ok(())
+    })
+    .unwrap();
+
+    let mut listed = xsh.directory("/big").unwrap();
+    listed.sort();
+    children.sort();
+    assert_eq!(listed, children);
+}
+
+#[test]
+fn watch_events_by_token() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    xsh.mkdir("/w/first").unwrap();
+    xsh.mkdir("/w/second").unwrap();
+    let first = xsh.create_watch("/w/first", "first").unwrap();
+    let second = xsh.create_watch("/w/second", "second").unwrap();
+
+    /* Fired when the watches are set */
+    assert_eq!(first.read_watch().unwrap(), event("/w/first", "first"));
+    assert_eq!(second.read_watch().unwrap(), event("/w/second", "second"));
+
+    xsh.write_str("/w/second/node", "x").unwrap();
+    assert_eq!(
+        second.read_watch_timeout(TIMEOUT).unwrap(),
+        event("/w/second/node", "second")
+    );
+    assert!(first.try_read_watch().unwrap().is_none());
+
+    xsh.write_str("/w/first", "x").unwrap();
+    assert_eq!(
+        first.read_watch_timeout(TIMEOUT).unwrap(),
+        event("/w/first", "first")
+    );
+    assert!(second.try_read_watch().unwrap().is_none());
+
+    assert!(matches!(
+        xsh.create_watch("/w/other", "first"),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists
+    ));
+}
+
+#[test]
+fn reset_watches() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    let watch = xsh.create_watch("/w", "t").unwrap();
+    watch.read_watch().unwrap();
+
+    xsh.reset_watches().unwrap();
+    let e = watch.read_watch().unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(ECANCELED));
+
+    /* The token is free again, and dropping the old watch leaves it alone */
+    let again = xsh.create_watch("/w", "t").unwrap();
+    again.read_watch().unwrap();
+    drop(watch);
+    xsh.write_str("/w", "x").unwrap();
+    assert_eq!(again.read_watch_timeout(TIMEOUT).unwrap(), event("/w", "t"));
+}
+
+#[test]
+fn transaction_commit() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    let tx = xsh.transaction_start().unwrap();
+    tx.write_str("/tx/a", "1").unwrap();
+    assert_eq!(tx.read_str("/tx/a").unwrap(), "1");
+    /* Not visible outside of the transaction until committed */
+    assert_eq!(
+        xsh.read_str("/tx/a").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    tx.commit().unwrap();
+    assert_eq!(xsh.read_str("/tx/a").unwrap(), "1");
+
+    let tx = xsh.transaction_start().unwrap();
+    tx.write_str("/tx/a", "2").unwrap();
+    tx.abort().unwrap();
+    assert_eq!(xsh.read_str("/tx/a").unwrap(), "1");
+}
+
+#[test]
+fn transaction_conflict() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+    let other = xenstored.handle().unwrap();
+
+    xsh.write_str("/tx/a", "0").unwrap();
+
+    let tx = xsh.transaction_start().unwrap();
+    assert_eq!(tx.read_str("/tx/a").unwrap(), "0");
+    tx.write_str("/tx/a", "1").unwrap();
+    other.write_str("/tx/a", "2").unwrap();
+
+    let e = tx.commit().unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(EAGAIN));
+    assert_eq!(xsh.read_str("/tx/a").unwrap(), "2");
+
+    /* with_transaction() starts over until it goes through */
+    let mut attempts = 0;
+    xsh.with_transaction(|tx| {
+        attempts += 1;
+        let value: u32 = tx.read_str("/tx/a")?.parse().unwrap();
+        if attempts == 1 {
+            other.write_str("/tx/a", "10")?;
+        }
+        tx.write_str("/tx/a", &(value + 1).to_string())
+    })
+    .unwrap();
+    assert_eq!(attempts, 2);
+    assert_eq!(xsh.read_str("/tx/a").unwrap(), "11");
+}
+
+#[test]
+fn permissions() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    let guest = xenstored.handle_as(1).unwrap();
+
+    /* Relative paths are in the domain's home, which it owns */
+    guest.write_str("data", "mine").unwrap();
+    assert_eq!(dom0.read_str("/local/domain/1/data").unwrap(), "mine");
+
+    dom0.write_str("/private", "secret").unwrap();
+    let e = guest.read_str("/private").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(EACCES));
+    let e = guest.write_str("/private", "x").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(EACCES));
+
+    dom0.grant_read_access("/private", 1).unwrap();
+    assert_eq!(guest.read_str("/private").unwrap(), "secret");
+    let e = guest.write_str("/private", "x").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(EACCES));
+
+    dom0.set_perms(
+        "/private",
+        &[
+            XsPermission::new(0, XsAccess::None),
+            XsPermission::new(1, XsAccess::Both),
+        ],
+    )
+    .unwrap();
+    guest.write_str("/private", "x").unwrap();
+    assert_eq!(dom0.read_str("/private").unwrap(), "x");
+
+    /* Other domains' homes are off limits */
+    let other = xenstored.handle_as(2).unwrap();
+    other.write_str("data", "theirs").unwrap();
+    let e = guest.read_str("/local/domain/2/data").unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(EACCES));
+}
+
+#[test]
+fn domain_paths() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    let guest = xenstored.handle_as(1).unwrap();
+
+    assert_eq!(dom0.domain_path(1).unwrap(), XsPath::domain(1));
+    assert_eq!(
+        dom0.domain_devices(1).unwrap().as_str(),
+        "/local/domain/1/device"
+    );
+    assert_eq!(
+        dom0.domain_backends(0).unwrap().as_str(),
+        "/local/domain/0/backend"
+    );
+
+    assert_eq!(dom0.domain_name(1).unwrap_err().kind(), ErrorKind::NotFound);
+    dom0.write_str("/local/domain/1/name", "guest").unwrap();
+    assert_eq!(dom0.domain_name(1).unwrap(), "guest");
+
+    /* The same node, relative to the guest's home */
+    let name = XsPath::new("name").unwrap();
+    assert_eq!(guest.read_str(&name).unwrap(), "guest");
+    assert_eq!(guest.domain_name(1).unwrap(), "guest");
+}
+
+#[test]
+fn quota() {
+    let xenstored = MockXenStored::start().unwrap();
+    let guest = xenstored.handle_as(1).unwrap();
+
+    let e = guest.write_bytes("big", &[b'x'; 4000]).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(ENOSPC));
+
+    /* Over what fits in a packet at all */
+    let e = guest.write_bytes("big", &[b'x'; 5000]).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(E2BIG));
+}
+
+#[test]
+fn reconnect() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    let watch = xsh.create_watch("/rc", "t").unwrap();
+    watch.read_watch().unwrap();
+
+    xenstored.disconnect_clients();
+    let start = Instant::now();
+    while xsh.is_connected() {
+        assert!(start.elapsed() < TIMEOUT);
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    /* The next request connects again, and registers the watch again */
+    xsh.write_str("/rc/node", "x").unwrap();
+    assert!(xsh.is_connected());
+    assert_eq!(xsh.read_str("/rc/node").unwrap(), "x");
+
+    let mut events = Vec::new();
+    while events.last() != Some(&event("/rc/node", "t")) {
+        events.push(watch.read_watch_timeout(TIMEOUT).unwrap());
+    }
+    assert_eq!(events[0], event("/rc", "t"));
+}
+
+/* Whether fileno() of a watch becomes readable within `timeout` */
+fn readable(fd: i32, timeout: Duration) -> bool {
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+
+    poll(&mut fds, timeout.as_millis() as i32).unwrap() == 1
+}
+
+#[test]
+fn reconnect_polled() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    xsh.mkdir("/rc").unwrap();
+    let watch = xsh.create_watch("/rc", "t").unwrap();
+    watch.read_watch().unwrap();
+    let fd = watch.fileno().unwrap();
+    assert!(!readable(fd, Duration::ZERO));
+
+    /* Nothing but the connection going away wakes the poller up */
+    xenstored.disconnect_clients();
+    assert!(readable(fd, TIMEOUT));
+
+    /* Reading connects again, and registering the watch again fires it */
+    assert_eq!(
+        watch.read_watch_timeout(TIMEOUT).unwrap(),
+        event("/rc", "t")
+    );
+    assert!(xsh.is_connected());
+
+    xsh.write_str("/rc/node", "x").unwrap();
+    assert!(readable(fd, TIMEOUT));
+    assert_eq!(
+        watch.read_watch_timeout(TIMEOUT).unwrap(),
+        event("/rc/node", "t")
+    );
+    assert!(!readable(fd, Duration::ZERO));
+}
+
+#[test]
+fn request_timeout() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+
+    xsh.write_str("/slow", "1").unwrap();
+    xsh.set_request_timeout(Some(Duration::from_millis(100)));
+    assert_eq!(xsh.request_timeout(), Some(Duration::from_millis(100)));
+
+    xenstored.set_stalled(true);
+    let e = xsh.read_str("/slow").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+    /* The late reply is dropped, it doesn't get mixed up with the next one */
+    xenstored.set_stalled(false);
+    xsh.write_str("/slow", "2").unwrap();
+    assert_eq!(xsh.read_str("/slow").unwrap(), "2");
+}
@@ -0,0 +1,146 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{collections::BTreeMap, io::ErrorKind};
+
+use xen_store::{MockXenStored, XenStoreHandle, XsAccess, XsPermission, XsSubtree};
+
+fn node(value: &[u8], children: &[(&str, XsSubtree)]) -> XsSubtree {
+    XsSubtree {
+        value: value.to_vec(),
+        perms: vec![XsPermission::new(0, XsAccess::None)],
+        children: children
+            .iter()
+            .map(|(name, child)| (String::from(*name), child.clone()))
+            .collect(),
+    }
+}
+
+/* A bit of everything: nesting, empty and binary values, shared access */
+fn populate(xsh: &XenStoreHandle, path: &str) {
+    xsh.write_str(&format!("{}/name", path), "guest").unwrap();
+    xsh.write_bytes(&format!("{}/blob", path), b"\x00\xff\xfe")
+        .unwrap();
+    xsh.mkdir(&format!("{}/device/vif/0", path)).unwrap();
+    xsh.write_str(&format!("{}/device/vif/0/mac", path), "00:16:3e:00:00:01")
+        .unwrap();
+    xsh.set_perms(
+        &format!("{}/device", path),
+        &[
+            XsPermission::new(1, XsAccess::None),
+            XsPermission::new(0, XsAccess::Read),
+        ],
+    )
+    .unwrap();
+}
+
+#[test]
+fn export() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+    populate(&xsh, "/src");
+
+    let subtree = xsh.export("/src").unwrap();
+    assert_eq!(subtree.children["name"].value, b"guest");
+    assert_eq!(subtree.children["blob"].value, b"\x00\xff\xfe");
+
+    let device = &subtree.children["device"];
+    assert_eq!(
+        device.perms,
+        [
+            XsPermission::new(1, XsAccess::None),
+            XsPermission::new(0, XsAccess::Read),
+        ]
+    );
+    let vif = &device.children["vif"].children["0"];
+    assert_eq!(vif.value, b"");
+    assert_eq!(vif.children["mac"].value, b"00:16:3e:00:00:01");
+
+    assert_eq!(
+        xsh.export("/nowhere").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn export_import_round_trip() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+    populate(&xsh, "/src");
+    let subtree = xsh.export("/src").unwrap();
+
+    /* Whatever was there goes away */
+    xsh.write_str("/dst/stale", "x").unwrap();
+    xsh.import("/dst", &subtree).unwrap();
+    assert_eq!(xsh.export("/dst").unwrap(), subtree);
+    assert_eq!(
+        xsh.read_str("/dst/stale").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+
+    /* Nodes without permissions get the default ones */
+    let mut bare = node(b"top", &[("child", node(b"\x01", &[]))]);
+    bare.children.get_mut("child").unwrap().perms.clear();
+    xsh.import("/bare", &bare).unwrap();
+    let imported = xsh.export("/bare").unwrap();
+    assert_eq!(imported.children["child"].value, b"\x01");
+    assert!(!imported.children["child"].perms.is_empty());
+}
+
+#[test]
+fn import_at_root() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+    xsh.write_str("/old", "x").unwrap();
+
+    let mut root = xsh.export("/").unwrap();
+    root.children = BTreeMap::from([
+        (String::from("a"), node(b"1", &[("b", node(b"2", &[]))])),
+        (String::from("c"), node(b"\xff", &[])),
+    ]);
+
+    xsh.import("/", &root).unwrap();
+    assert_eq!(xsh.export("/").unwrap(), root);
+    assert_eq!(xsh.directory("/").unwrap(), ["a", "c"]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = xenstored.handle().unwrap();
+    populate(&xsh, "/src");
+    let subtree = xsh.export("/src").unwrap();
+
+    let json = serde_json::to_value(&subtree).unwrap();
+    /* Text stays readable, anything else is kept as bytes */
+    assert_eq!(json["children"]["name"]["value"], "guest");
+    assert_eq!(
+        json["children"]["blob"]["value"],
+        serde_json::json!([0, 255, 254])
+    );
+    assert_eq!(
+        json["children"]["device"]["perms"],
+        serde_json::json!(["n1", "r0"])
+    );
+
+    let decoded: XsSubtree = serde_json::from_value(json).unwrap();
+    assert_eq!(decoded, subtree);
+
+    /* Everything but the value can be left out */
+    let decoded: XsSubtree = serde_json::from_str(r#"{"value": "v"}"#).unwrap();
+    assert_eq!(
+        decoded,
+        XsSubtree {
+            value: b"v".to_vec(),
+            ..XsSubtree::default()
+        }
+    );
+}
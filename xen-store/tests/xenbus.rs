@@ -0,0 +1,360 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    cell::RefCell,
+    io::{Error, ErrorKind},
+    rc::Rc,
+};
+
+use xen_store::{
+    MockXenStored, XenStoreHandle, XenbusBackend, XenbusBackendDevice, XenbusBackendFactory,
+    XenbusDevice, XenbusDriver, XenbusRole, XenbusState, XsAccess, XsPermission,
+};
+
+const FRONTEND: u32 = 1;
+
+/* What the toolstack writes before starting the backend and the guest */
+fn add_device(dom0: &XenStoreHandle, devid: u32, backend: bool) {
+    let device = XenbusDevice::new("vtest", FRONTEND, 0, devid);
+    let frontend = device.frontend_path(dom0).unwrap();
+
+    dom0.write_str(&frontend.join("backend-id").unwrap(), "0")
+        .unwrap();
+    dom0.set_perms(
+        &frontend,
+        &[
+            XsPermission::new(FRONTEND, XsAccess::None),
+            XsPermission::new(0, XsAccess::Read),
+        ],
+    )
+    .unwrap();
+    dom0.set_perms(
+        &frontend.join("backend-id").unwrap(),
+        &[
+            XsPermission::new(FRONTEND, XsAccess::None),
+            XsPermission::new(0, XsAccess::Read),
+        ],
+    )
+    .unwrap();
+
+    if backend {
+        add_backend(dom0, devid);
+    }
+}
+
+fn add_backend(dom0: &XenStoreHandle, devid: u32) {
+    let device = XenbusDevice::new("vtest", FRONTEND, 0, devid);
+    let backend = device.backend_path(dom0).unwrap();
+
+    dom0.write_str(&backend.join("online").unwrap(), "1")
+        .unwrap();
+    dom0.set_perms(
+        &backend,
+        &[
+            XsPermission::new(0, XsAccess::None),
+            XsPermission::new(FRONTEND, XsAccess::Read),
+        ],
+    )
+    .unwrap();
+}
+
+#[test]
+fn device_paths() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    let device = XenbusDevice::new("vif", 3, 0, 1);
+
+    assert_eq!(
+        device.frontend_path(&dom0).unwrap().as_str(),
+        "/local/domain/3/device/vif/1"
+    );
+    assert_eq!(
+        device.backend_path(&dom0).unwrap().as_str(),
+        "/local/domain/0/backend/vif/3/1"
+    );
+
+    dom0.write_str("/local/domain/3/device/vif/1/backend-id", "0")
+        .unwrap();
+    assert_eq!(
+        XenbusDevice::from_frontend(&dom0, "vif", 3, 1).unwrap(),
+        device
+    );
+}
+
+#[test]
+fn driver_handshake() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    let guest = xenstored.handle_as(FRONTEND).unwrap();
+    add_device(&dom0, 0, true);
+
+    let device = XenbusDevice::from_frontend(&guest, "vtest", FRONTEND, 0).unwrap();
+    let backend = XenbusDriver::new(&dom0, device.clone(), XenbusRole::Backend).unwrap();
+    let mut frontend = XenbusDriver::new(&guest, device, XenbusRole::Frontend).unwrap();
+    assert_eq!(frontend.peer_state(), XenbusState::Unknown);
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let transitions = Rc::clone(&seen);
+    frontend.on_transition(move |_, old, new| {
+        transitions.borrow_mut().push((old, new));
+        Ok(())
+    });
+
+    backend.set_state(XenbusState::InitWait).unwrap();
+    assert_eq!(
+        frontend.wait_for_peer(&[XenbusState::InitWait]).unwrap(),
+        XenbusState::InitWait
+    );
+    frontend.set_state(XenbusState::Initialised).unwrap();
+    assert_eq!(backend.state().unwrap(), XenbusState::InitWait);
+    assert_eq!(frontend.state().unwrap(), XenbusState::Initialised);
+
+    backend.set_state(XenbusState::Connected).unwrap();
+    frontend.wait_for_peer(&[XenbusState::Connected]).unwrap();
+    assert_eq!(
+        *seen.borrow(),
+        [
+            (XenbusState::Unknown, XenbusState::InitWait),
+            (XenbusState::InitWait, XenbusState::Connected),
+        ]
+    );
+
+    assert!(backend.online().unwrap());
+    backend.set_hotplug_status("connected").unwrap();
+    assert_eq!(
+        frontend.hotplug_status().unwrap().as_deref(),
+        Some("connected")
+    );
+}
+
+#[test]
+fn drivers_sharing_a_peer() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    add_device(&dom0, 0, true);
+
+    /* i.e a backend and a tool watching the same frontend, on one handle */
+    let device = XenbusDevice::new("vtest", FRONTEND, 0, 0);
+    let first = XenbusDriver::new(&dom0, device.clone(), XenbusRole::Backend).unwrap();
+    let second = XenbusDriver::new(&dom0, device, XenbusRole::Backend).unwrap();
+
+    assert_eq!(first.peer_path(), second.peer_path());
+}
+
+#[derive(Default)]
+struct Calls {
+    created: Vec<u32>,
+    connected: Vec<u32>,
+    disconnected: Vec<u32>,
+    destroyed: Vec<u32>,
+}
+
+struct TestFactory {
+    calls: Rc<RefCell<Calls>>,
+    /* Devices the factory refuses to create */
+    broken: Vec<u32>,
+}
+
+struct TestDevice {
+    devid: u32,
+    calls: Rc<RefCell<Calls>>,
+}
+
+impl XenbusBackendFactory for TestFactory {
+    type Device = TestDevice;
+
+    fn create(&mut self, driver: &XenbusDriver<'_>) -> Result<TestDevice, Error> {
+        let devid = driver.device().devid;
+
+        if self.broken.contains(&devid) {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        self.calls.borrow_mut().created.push(devid);
+
+        Ok(TestDevice {
+            devid,
+            calls: Rc::clone(&self.calls),
+        })
+    }
+}
+
+impl XenbusBackendDevice for TestDevice {
+    fn connect(&mut self, _driver: &XenbusDriver<'_>) -> Result<(), Error> {
+        self.calls.borrow_mut().connected.push(self.devid);
+        Ok(())
+    }
+
+    fn disconnect(&mut self, _driver: &XenbusDriver<'_>) -> Result<(), Error> {
+        self.calls.borrow_mut().disconnected.push(self.devid);
+        Ok(())
+    }
+
+    fn destroy(&mut self, _driver: &XenbusDriver<'_>) {
+        self.calls.borrow_mut().destroyed.push(self.devid);
+    }
+}
+
+fn factory(broken: &[u32]) -> (TestFactory, Rc<RefCell<Calls>>) {
+    let calls = Rc::new(RefCell::new(Calls::default()));
+    let factory = TestFactory {
+        calls: Rc::clone(&calls),
+        broken: broken.to_vec(),
+    };
+
+    (factory, calls)
+}
+
+/* Process events until `done` holds, each process_events() call blocks */
+fn run_until<F, P>(backend: &mut XenbusBackend<'_, F>, mut done: P)
+where
+    F: XenbusBackendFactory,
+    P: FnMut(&XenbusBackend<'_, F>) -> bool,
+{
+    for _ in 0..32 {
+        if done(backend) {
+            return;
+        }
+        backend.process_events().unwrap();
+    }
+
+    panic!("backend didn't get there");
+}
+
+#[test]
+fn backend_lifecycle() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    let guest = xenstored.handle_as(FRONTEND).unwrap();
+    add_device(&dom0, 0, true);
+
+    let (factory, calls) = factory(&[]);
+    let mut backend = XenbusBackend::new(&dom0, "vtest", factory).unwrap();
+    assert_eq!(backend.devices(), [(FRONTEND, 0)]);
+    assert_eq!(calls.borrow().created, [0]);
+
+    let device = XenbusDevice::new("vtest", FRONTEND, 0, 0);
+    let backend_path = device.backend_path(&dom0).unwrap();
+    let frontend = XenbusDriver::new(&guest, device, XenbusRole::Frontend).unwrap();
+    assert_eq!(
+        XenbusState::read(&dom0, &backend_path).unwrap(),
+        XenbusState::InitWait
+    );
+
+    frontend.set_state(XenbusState::Initialised).unwrap();
+    run_until(&mut backend, |_| !calls.borrow().connected.is_empty());
+    assert_eq!(
+        XenbusState::read(&dom0, &backend_path).unwrap(),
+        XenbusState::Connected
+    );
+
+    /* The toolstack takes the device away */
+    dom0.write_str(&backend_path.join("online").unwrap(), "0")
+        .unwrap();
+    frontend.set_state(XenbusState::Closing).unwrap();
+    run_until(&mut backend, |_| !calls.borrow().disconnected.is_empty());
+    assert_eq!(
+        XenbusState::read(&dom0, &backend_path).unwrap(),
+        XenbusState::Closing
+    );
+
+    frontend.set_state(XenbusState::Closed).unwrap();
+    run_until(&mut backend, |backend| backend.devices().is_empty());
+    assert_eq!(calls.borrow().destroyed, [0]);
+    assert_eq!(
+        dom0.read_str(&backend_path).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn backend_skips_broken_devices() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    add_device(&dom0, 0, true);
+    add_device(&dom0, 1, true);
+
+    let (factory, calls) = factory(&[0]);
+    let mut backend = XenbusBackend::new(&dom0, "vtest", factory).unwrap();
+    assert_eq!(backend.devices(), [(FRONTEND, 1)]);
+    let errors = backend.take_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, (FRONTEND, 0));
+    assert_eq!(errors[0].1.kind(), ErrorKind::Other);
+    assert!(backend.take_errors().is_empty());
+
+    /* A new device is still picked up, the broken one is tried again */
+    add_device(&dom0, 2, true);
+    run_until(&mut backend, |backend| backend.devices().len() == 2);
+    assert_eq!(calls.borrow().created, [1, 2]);
+    assert!(backend
+        .take_errors()
+        .iter()
+        .all(|(key, _)| *key == (FRONTEND, 0)));
+}
+
+#[test]
+fn backend_waits_for_frontend() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+
+    /* The backend nodes are there before the frontend ones */
+    add_backend(&dom0, 0);
+    let (factory, calls) = factory(&[]);
+    let mut backend = XenbusBackend::new(&dom0, "vtest", factory).unwrap();
+    let device = XenbusDevice::new("vtest", FRONTEND, 0, 0);
+    let backend_path = device.backend_path(&dom0).unwrap();
+    assert_eq!(backend.devices(), [(FRONTEND, 0)]);
+    assert!(dom0.read_str(&backend_path.join("online").unwrap()).is_ok());
+
+    add_device(&dom0, 0, false);
+    dom0.write_str(
+        &device.frontend_path(&dom0).unwrap().join("state").unwrap(),
+        &XenbusState::Initialising.to_string(),
+    )
+    .unwrap();
+    backend.process_events().unwrap();
+    assert_eq!(backend.devices(), [(FRONTEND, 0)]);
+    assert_eq!(
+        XenbusState::read(&dom0, &backend_path).unwrap(),
+        XenbusState::InitWait
+    );
+
+    /* Removing the backend node is what makes the device go */
+    dom0.rm(&backend_path).unwrap();
+    run_until(&mut backend, |backend| backend.devices().is_empty());
+    assert_eq!(calls.borrow().destroyed, [0]);
+}
+
+#[test]
+fn backend_reaps_released_domains() {
+    let xenstored = MockXenStored::start().unwrap();
+    let dom0 = xenstored.handle().unwrap();
+    add_device(&dom0, 0, true);
+    dom0.introduce(FRONTEND, 0, 0).unwrap();
+
+    let (factory, calls) = factory(&[]);
+    let mut backend = XenbusBackend::new(&dom0, "vtest", factory).unwrap();
+    assert_eq!(backend.devices(), [(FRONTEND, 0)]);
+
+    /* The domain is destroyed, the toolstack cleans up its home */
+    dom0.rm(&format!("/local/domain/{}", FRONTEND)).unwrap();
+    dom0.release(FRONTEND).unwrap();
+    run_until(&mut backend, |backend| backend.devices().is_empty());
+    assert_eq!(calls.borrow().destroyed, [0]);
+
+    let device = XenbusDevice::new("vtest", FRONTEND, 0, 0);
+    assert_eq!(
+        dom0.read_str(&device.backend_path(&dom0).unwrap())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::NotFound
+    );
+}
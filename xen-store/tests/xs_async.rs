@@ -0,0 +1,122 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{future::poll_fn, io::ErrorKind, pin::Pin, time::Duration};
+
+use futures_core::Stream;
+use tokio::time::timeout;
+use xen_store::{AsyncXenStoreHandle, MockXenStored, WatchEvent, WatchStream};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn connect(xenstored: &MockXenStored) -> AsyncXenStoreHandle {
+    AsyncXenStoreHandle::with_transport(&xenstored.transport())
+        .await
+        .unwrap()
+}
+
+/* None once the stream is over */
+async fn next(stream: &mut WatchStream) -> Option<WatchEvent> {
+    timeout(TIMEOUT, poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)))
+        .await
+        .unwrap()
+}
+
+fn event(path: &str, token: &str) -> WatchEvent {
+    WatchEvent {
+        path: String::from(path),
+        token: String::from(token),
+    }
+}
+
+#[tokio::test]
+async fn read_write_directory() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = connect(&xenstored).await;
+
+    xsh.write("/test/a", "1").await.unwrap();
+    xsh.write_bytes("/test/b", b"\x00\xff").await.unwrap();
+
+    assert_eq!(xsh.read("/test/a").await.unwrap(), "1");
+    assert_eq!(xsh.read_bytes("/test/b").await.unwrap(), b"\x00\xff");
+    assert_eq!(
+        xsh.read("/test/b").await.unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+    assert_eq!(xsh.directory("/test").await.unwrap(), ["a", "b"]);
+    assert_eq!(
+        xsh.read("/test/c").await.unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[tokio::test]
+async fn directory_part() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = connect(&xenstored).await;
+
+    /* Far more than fits in a single XS_DIRECTORY reply */
+    let children: Vec<String> = (0..1000).map(|i| format!("child-{:04}", i)).collect();
+    for child in children.iter() {
+        xsh.write(&format!("/big/{}", child), "").await.unwrap();
+    }
+
+    let mut listed = xsh.directory("/big").await.unwrap();
+    listed.sort();
+    assert_eq!(listed, children);
+}
+
+#[tokio::test]
+async fn watch_stream() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = connect(&xenstored).await;
+    xsh.write("/w/a", "").await.unwrap();
+    xsh.write("/w/b", "").await.unwrap();
+
+    let mut first = xsh.watch("/w/a", "first").await.unwrap();
+    let mut second = xsh.watch("/w/b", "second").await.unwrap();
+    assert_eq!(next(&mut first).await, Some(event("/w/a", "first")));
+    assert_eq!(next(&mut second).await, Some(event("/w/b", "second")));
+
+    xsh.write("/w/b/node", "x").await.unwrap();
+    xsh.write("/w/a", "x").await.unwrap();
+    assert_eq!(next(&mut second).await, Some(event("/w/b/node", "second")));
+    assert_eq!(next(&mut first).await, Some(event("/w/a", "first")));
+
+    assert_eq!(
+        xsh.watch("/w/c", "first").await.err().unwrap().kind(),
+        ErrorKind::AlreadyExists
+    );
+    /* The token is free again once the background XS_UNWATCH went out */
+    drop(first);
+    xsh.read("/w/a").await.unwrap();
+    let mut again = xsh.watch("/w/a", "first").await.unwrap();
+    assert_eq!(next(&mut again).await, Some(event("/w/a", "first")));
+}
+
+#[tokio::test]
+async fn dropped_connection() {
+    let xenstored = MockXenStored::start().unwrap();
+    let xsh = connect(&xenstored).await;
+    xsh.write("/w", "").await.unwrap();
+
+    let mut watch = xsh.watch("/w", "t").await.unwrap();
+    assert_eq!(next(&mut watch).await, Some(event("/w", "t")));
+
+    /* Streams end and requests fail, they don't hang */
+    xenstored.disconnect_clients();
+    assert_eq!(next(&mut watch).await, None);
+    let e = timeout(TIMEOUT, xsh.read("/w")).await.unwrap().unwrap_err();
+    assert!(matches!(
+        e.kind(),
+        ErrorKind::NotConnected | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    ));
+    assert!(xsh.watch("/w", "u").await.is_err());
+}
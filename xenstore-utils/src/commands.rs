@@ -0,0 +1,378 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+use serde_json::{json, Map, Value};
+use xen_store::{XenStoreHandle, XenStoreTransaction, XsPermission};
+
+use crate::options::Options;
+
+fn invalid_input(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/*
+ * Errors are reported along with the path they happened on, except for
+ * transaction conflicts which with_transaction() has to recognize.
+ */
+fn at(path: &str, e: Error) -> Error {
+    match e.raw_os_error() {
+        Some(libc::EAGAIN) => e,
+        _ => Error::new(e.kind(), format!("{}: {}", path, e)),
+    }
+}
+
+fn join(path: &str, child: &str) -> String {
+    match path.strip_suffix('/') {
+        Some(parent) => format!("{}/{}", parent, child),
+        None => format!("{}/{}", path, child),
+    }
+}
+
+/* Backslash escapes for anything not printable, as the C tools do */
+fn escape(value: &[u8]) -> String {
+    let mut escaped = String::new();
+
+    for byte in value {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            0x20..=0x7e => escaped.push(*byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+
+    escaped
+}
+
+fn unescape(value: &str) -> Result<Vec<u8>, Error> {
+    let mut bytes = value.bytes();
+    let mut unescaped = Vec::new();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            unescaped.push(byte);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'\\') => unescaped.push(b'\\'),
+            Some(b't') => unescaped.push(b'\t'),
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(b'r') => unescaped.push(b'\r'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let byte = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| invalid_input("bad \\x escape"))?;
+                unescaped.push(byte);
+            }
+            _ => return Err(invalid_input("bad escape sequence")),
+        }
+    }
+
+    Ok(unescaped)
+}
+
+fn show(value: &[u8], options: &Options) -> String {
+    match options.flag('R') {
+        true => String::from_utf8_lossy(value).into_owned(),
+        false => escape(value),
+    }
+}
+
+fn perms_string(perms: &[XsPermission]) -> String {
+    perms
+        .iter()
+        .map(|perm| perm.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn need_paths(options: &Options) -> Result<(), Error> {
+    match options.positional.is_empty() {
+        true => Err(invalid_input("no path given")),
+        false => Ok(()),
+    }
+}
+
+pub fn read(xsh: &XenStoreHandle, options: &Options) -> Result<(), Error> {
+    need_paths(options)?;
+
+    let mut values = Map::new();
+    for path in options.positional.iter() {
+        let value = xsh.read_bytes(path).map_err(|e| at(path, e))?;
+
+        if options.flag('j') {
+            values.insert(path.clone(), Value::String(show(&value, options)));
+        } else if options.flag('p') {
+            println!("{}: {}", path, show(&value, options));
+        } else {
+            println!("{}", show(&value, options));
+        }
+    }
+
+    if options.flag('j') {
+        println!("{}", serde_json::to_string_pretty(&values)?);
+    }
+
+    Ok(())
+}
+
+pub fn write(xsh: &XenStoreHandle, options: &Options) -> Result<(), Error> {
+    let args = &options.positional;
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(invalid_input("expected pairs of path and value"));
+    }
+
+    for pair in args.chunks(2) {
+        let value = match options.flag('R') {
+            true => pair[1].clone().into_bytes(),
+            false => unescape(&pair[1])?,
+        };
+        xsh.write_bytes(&pair[0], &value)
+            .map_err(|e| at(&pair[0], e))?;
+    }
+
+    Ok(())
+}
+
+/* A node and everything below it, as seen within one transaction */
+struct Listing {
+    path: String,
+    /* None if we aren't allowed to read it */
+    value: Option<Vec<u8>>,
+    perms: Vec<XsPermission>,
+    children: Vec<(String, Listing)>,
+}
+
+fn list(tx: &XenStoreTransaction, path: &str) -> Result<Listing, Error> {
+    let value = match tx.read_bytes(path) {
+        Ok(value) => Some(value),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => None,
+        Err(e) => return Err(at(path, e)),
+    };
+    let perms = match tx.get_perms(path) {
+        Ok(perms) => perms,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Vec::new(),
+        Err(e) => return Err(at(path, e)),
+    };
+    let names = match tx.directory(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Vec::new(),
+        Err(e) => return Err(at(path, e)),
+    };
+
+    let mut children = Vec::new();
+    for name in names {
+        let child = list(tx, &join(path, &name))?;
+        children.push((name, child));
+    }
+
+    Ok(Listing {
+        path: String::from(path),
+        value,
+        perms,
+        children,
+    })
+}
+
+fn print_listing(listing: &Listing, depth: usize, options: &Options) {
+    for (name, child) in listing.children.iter() {
+        let mut line = match options.flag('f') {
+            true => child.path.clone(),
+            false => format!("{:depth$}{}", "", name, depth = depth),
+        };
+
+        if let Some(value) = &child.value {
+            line.push_str(&format!(" = \"{}\"", show(value, options)));
+        }
+        if options.flag('p') {
+            line.push_str(&format!("  ({})", perms_string(&child.perms)));
+        }
+        println!("{}", line);
+
+        print_listing(child, depth + 1, options);
+    }
+}
+
+fn listing_json(listing: &Listing, options: &Options) -> Value {
+    let mut node = Map::new();
+    let children: Map<String, Value> = listing
+        .children
+        .iter()
+        .map(|(name, child)| (name.clone(), listing_json(child, options)))
+        .collect();
+
+    node.insert(
+        String::from("value"),
+        match &listing.value {
+            Some(value) => Value::String(show(value, options)),
+            None => Value::Null,
+        },
+    );
+    if options.flag('p') {
+        let perms = listing.perms.iter().map(|perm| json!(perm.to_string()));
+        node.insert(String::from("perms"), Value::Array(perms.collect()));
+    }
+    node.insert(String::from("children"), Value::Object(children));
+
+    Value::Object(node)
+}
+
+pub fn ls(xsh: &XenStoreHandle, options: &Options) -> Result<(), Error> {
+    let path = match options.positional.as_slice() {
+        [] => "/",
+        [path] => path.as_str(),
+        _ => return Err(invalid_input("only one path can be listed")),
+    };
+
+    /* In a transaction so that the listing is consistent */
+    let listing = xsh.with_transaction(|tx| list(tx, path))?;
+
+    if options.flag('j') {
+        let listing = listing_json(&listing, options);
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+    } else {
+        print_listing(&listing, 0, options);
+    }
+
+    Ok(())
+}
+
+pub fn rm(xsh: &XenStoreHandle, options: &Options) -> Result<(), Error> {
+    need_paths(options)?;
+
+    for path in options.positional.iter() {
+        xsh.rm(path).map_err(|e| at(path, e))?;
+
+        if !options.flag('t') {
+            continue;
+        }
+
+        /* Then the parents left empty, stopping at the first we can't remove */
+        let mut parent = path.trim_end_matches('/');
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            if dir.is_empty() {
+                break;
+            }
+            match xsh.directory(dir) {
+                Ok(children) if children.is_empty() => (),
+                _ => break,
+            }
+            if xsh.rm(dir).is_err() {
+                break;
+            }
+            parent = dir;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn watch(xsh: &XenStoreHandle, options: &Options) -> Result<(), Error> {
+    need_paths(options)?;
+
+    let mut count = match options.value('n') {
+        Some(count) => Some(
+            count
+                .parse::<u64>()
+                .map_err(|_| invalid_input("-n needs a number"))?,
+        ),
+        None => None,
+    };
+
+    /* The path doubles as the token */
+    let mut watches = Vec::new();
+    for path in options.positional.iter() {
+        watches.push(xsh.create_watch(path, path).map_err(|e| at(path, e))?);
+    }
+
+    let mut poll_fds = Vec::new();
+    for watch in watches.iter() {
+        poll_fds.push(PollFd::new(watch.fileno()?, PollFlags::POLLIN));
+    }
+
+    while count != Some(0) {
+        match poll(&mut poll_fds, -1) {
+            Ok(_) => (),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        for watch in watches.iter() {
+            while count != Some(0) {
+                let event = match watch.try_read_watch()? {
+                    Some(event) => event,
+                    None => break,
+                };
+
+                if options.flag('j') {
+                    println!("{}", json!({"path": event.path, "token": event.token}));
+                } else {
+                    println!("{}", event.path);
+                }
+                count = count.map(|count| count - 1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn subtree(tx: &XenStoreTransaction, path: &str, paths: &mut Vec<String>) -> Result<(), Error> {
+    paths.push(String::from(path));
+
+    for name in tx.directory(path).map_err(|e| at(path, e))? {
+        subtree(tx, &join(path, &name), paths)?;
+    }
+
+    Ok(())
+}
+
+pub fn chmod(xsh: &XenStoreHandle, options: &Options) -> Result<(), Error> {
+    let (path, perms) = match options.positional.split_first() {
+        Some((path, perms)) if !perms.is_empty() => (path, perms),
+        _ => return Err(invalid_input("expected a path and permissions")),
+    };
+    let perms = perms
+        .iter()
+        .map(|perm| {
+            XsPermission::from_str(perm)
+                .map_err(|_| invalid_input(&format!("bad permission {}", perm)))
+        })
+        .collect::<Result<Vec<XsPermission>, Error>>()?;
+
+    xsh.with_transaction(|tx| {
+        let mut paths = Vec::new();
+        match options.flag('r') {
+            true => subtree(tx, path, &mut paths)?,
+            false => paths.push(path.clone()),
+        }
+
+        for path in paths.iter() {
+            tx.set_perms(path, &perms).map_err(|e| at(path, e))?;
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+mod commands;
+mod options;
+
+use std::{env, path::Path, process};
+
+use xen_store::{XenStoreHandle, XenStoreTransport};
+
+use options::Options;
+
+const USAGE: &str = "\
+Usage: xenstore [-s <path>] <command> [options] <args>
+
+  read [-R] [-p] [-j] <path>...                   print the value of nodes
+  write [-R] <path> <value> [<path> <value>]...   set the value of nodes
+  ls [-f] [-p] [-j] [-R] [<path>]                 list a subtree
+  rm [-t] <path>...                               remove nodes
+  watch [-n <count>] [-j] <path>...               print events on nodes
+  chmod [-r] <path> <perm>...                     set the permissions of nodes
+
+  -s <path>   socket or device to reach xenstored through
+  -R          don't escape (read, ls) or unescape (write) values
+  -p          prefix values with paths (read), show permissions (ls)
+  -j          print JSON
+  -f          print full paths (ls)
+  -t          also remove empty parents (rm)
+  -n <count>  exit after that many events (watch)
+  -r          apply to the whole subtree (chmod)
+";
+
+type Command = fn(&XenStoreHandle, &Options) -> Result<(), std::io::Error>;
+
+fn command(name: &str) -> Option<(Command, &'static str, &'static str)> {
+    /* The options the command takes, and those having a value */
+    match name {
+        "read" => Some((commands::read, "Rpj", "")),
+        "write" => Some((commands::write, "R", "")),
+        "ls" | "list" => Some((commands::ls, "fpjR", "")),
+        "rm" => Some((commands::rm, "t", "")),
+        "watch" => Some((commands::watch, "j", "n")),
+        "chmod" => Some((commands::chmod, "r", "")),
+        _ => None,
+    }
+}
+
+fn usage(prog: &str, error: &str) -> ! {
+    eprintln!("{}: {}\n\n{}", prog, error, USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let argv0 = Path::new(&args.remove(0))
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    /* -s may come before the command as well as after it */
+    let mut socket = None;
+    if args.first().map(String::as_str) == Some("-s") {
+        if args.len() < 2 {
+            usage(&argv0, "-s needs a value");
+        }
+        socket = Some(args.remove(1));
+        args.remove(0);
+    }
+
+    /* Called through a xenstore-<command> link, or as xenstore <command> */
+    let name = match argv0.strip_prefix("xenstore-") {
+        Some(name) => String::from(name),
+        None if args.is_empty() => usage(&argv0, "no command given"),
+        None => args.remove(0),
+    };
+    if name == "help" || args.iter().any(|arg| arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+
+    let prog = format!("xenstore-{}", name);
+    let (function, flags, valued) =
+        command(&name).unwrap_or_else(|| usage(&argv0, &format!("unknown command {}", name)));
+    let options =
+        Options::parse(args, flags, &format!("s{}", valued)).unwrap_or_else(|e| usage(&prog, &e));
+
+    let xsh = match options.value('s').or(socket.as_deref()) {
+        Some(path) => XenStoreTransport::from_path(path)
+            .and_then(|transport| XenStoreHandle::with_transport(&transport)),
+        None => XenStoreHandle::new(),
+    };
+    let xsh = xsh.unwrap_or_else(|e| {
+        eprintln!("{}: can't connect to xenstored: {}", prog, e);
+        process::exit(1);
+    });
+
+    if let Err(e) = function(&xsh, &options) {
+        if e.kind() == std::io::ErrorKind::InvalidInput {
+            usage(&prog, &e.to_string());
+        }
+        eprintln!("{}: {}", prog, e);
+        process::exit(1);
+    }
+}
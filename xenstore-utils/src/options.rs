@@ -0,0 +1,74 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::collections::HashMap;
+
+/*
+ * Command line of one command, getopt style: options come first and stop at
+ * "--" or the first argument that isn't one, so values starting with '-' can
+ * still be written.
+ */
+pub struct Options {
+    flags: Vec<char>,
+    values: HashMap<char, String>,
+    pub positional: Vec<String>,
+}
+
+impl Options {
+    /* `flags` are the options without a value, `valued` those with one */
+    pub fn parse(args: Vec<String>, flags: &str, valued: &str) -> Result<Self, String> {
+        let mut options = Options {
+            flags: Vec::new(),
+            values: HashMap::new(),
+            positional: Vec::new(),
+        };
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+            if !arg.starts_with('-') || arg.len() == 1 {
+                options.positional.push(arg);
+                break;
+            }
+
+            for (i, opt) in arg.char_indices().skip(1) {
+                if flags.contains(opt) {
+                    options.flags.push(opt);
+                } else if valued.contains(opt) {
+                    /* Either glued to the option or the next argument */
+                    let value = match &arg[i + 1..] {
+                        "" => args
+                            .next()
+                            .ok_or_else(|| format!("-{} needs a value", opt))?,
+                        rest => String::from(rest),
+                    };
+                    options.values.insert(opt, value);
+                    break;
+                } else {
+                    return Err(format!("unknown option -{}", opt));
+                }
+            }
+        }
+
+        options.positional.extend(args);
+
+        Ok(options)
+    }
+
+    pub fn flag(&self, opt: char) -> bool {
+        self.flags.contains(&opt)
+    }
+
+    pub fn value(&self, opt: char) -> Option<&str> {
+        self.values.get(&opt).map(String::as_str)
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    os::unix::{io::AsRawFd, net::UnixStream},
+};
+
+use xen_store::XsPacket;
+
+use crate::ring::XenStoreRing;
+
+pub enum Transport {
+    Socket(UnixStream),
+    Ring {
+        ring: XenStoreRing,
+        domid: u32,
+        /* Our end of the domain's event channel */
+        port: u32,
+    },
+}
+
+/* A client of the daemon and the bytes going either way */
+pub struct Connection {
+    pub transport: Transport,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+}
+
+impl Connection {
+    pub fn new(transport: Transport) -> Self {
+        Connection {
+            transport,
+            input: Vec::new(),
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn fd(&self) -> Option<i32> {
+        match &self.transport {
+            Transport::Socket(stream) => Some(stream.as_raw_fd()),
+            Transport::Ring { .. } => None,
+        }
+    }
+
+    pub fn port(&self) -> Option<u32> {
+        match &self.transport {
+            Transport::Socket(_) => None,
+            Transport::Ring { port, .. } => Some(*port),
+        }
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /*
+     * Pull in what the client sent and return the complete packets.  Returns
+     * whether a ring peer needs notifying along with them.
+     */
+    pub fn receive(&mut self) -> Result<(Vec<XsPacket>, bool), std::io::Error> {
+        let mut notify = false;
+
+        match &mut self.transport {
+            Transport::Socket(stream) => {
+                let mut buf = [0u8; 4096];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof)),
+                        Ok(len) => self.input.extend_from_slice(&buf[..len]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Transport::Ring { ring, .. } => notify = ring.read_requests(&mut self.input)?,
+        }
+
+        let mut packets = Vec::new();
+        let mut used = 0;
+        while let Some((packet, len)) = XsPacket::decode(&self.input[used..])? {
+            packets.push(packet);
+            used += len;
+        }
+        self.input.drain(..used);
+
+        Ok((packets, notify))
+    }
+
+    pub fn send(&mut self, packet: &XsPacket) {
+        self.output.extend(packet.encode());
+    }
+
+    /* Push out as much as possible, returns whether a ring peer needs notifying */
+    pub fn flush(&mut self) -> Result<bool, std::io::Error> {
+        let mut notify = false;
+
+        while !self.output.is_empty() {
+            let (front, _) = self.output.as_slices();
+            let written = match &mut self.transport {
+                Transport::Socket(stream) => match stream.write(front) {
+                    Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero)),
+                    Ok(len) => len,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                },
+                Transport::Ring { ring, .. } => ring.write_responses(front)?,
+            };
+
+            if written == 0 {
+                break;
+            }
+            notify = true;
+            self.output.drain(..written);
+        }
+
+        Ok(notify && matches!(self.transport, Transport::Ring { .. }))
+    }
+}
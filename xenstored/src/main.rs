@@ -0,0 +1,469 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+#[macro_use]
+extern crate vmm_sys_util;
+
+mod connection;
+mod ring;
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::ErrorKind,
+    os::unix::{fs::PermissionsExt, io::AsRawFd, net::UnixListener},
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+use xen_bindings::bindings::VIRQ_DOM_EXC;
+use xen_ioctls::{xc_domain_getinfo, XenEventChannelHandle};
+use xen_store::{
+    XenStoreServer, XsDomainEvent, XsQuota, XsRequest, XENSTORED_PATH_ENV, XENSTORED_SOCKET,
+};
+
+use connection::{Connection, Transport};
+use ring::XenStoreRing;
+
+const USAGE: &str = "\
+Usage: xenstored [options]
+
+  --entry-nb <n>      maximum number of nodes owned by a domain
+  --entry-size <n>    maximum size of a node, in bytes
+  --perm-nb <n>       maximum number of permissions on a node
+  --watch-nb <n>      maximum number of watches of a domain
+  --transaction <n>   maximum number of transactions of a domain
+  --no-domain-init    only serve the unix socket
+  --help              show this help
+";
+
+/* How often domains are checked for having died, without VIRQ_DOM_EXC */
+const DOMAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Options {
+    quota: XsQuota,
+    domain_init: bool,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        quota: XsQuota::default(),
+        domain_init: true,
+    };
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let field = match arg.as_str() {
+            "--entry-nb" => &mut options.quota.nodes,
+            "--entry-size" => &mut options.quota.node_size,
+            "--perm-nb" => &mut options.quota.perms,
+            "--watch-nb" => &mut options.quota.watches,
+            "--transaction" => &mut options.quota.transactions,
+            "--no-domain-init" => {
+                options.domain_init = false;
+                continue;
+            }
+            "--help" | "-h" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("unknown option {}", arg)),
+        };
+
+        *field = args
+            .next()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| format!("{} needs a number", arg))?;
+    }
+
+    Ok(options)
+}
+
+struct Daemon {
+    server: XenStoreServer,
+    listener: UnixListener,
+    evtchn: Option<XenEventChannelHandle>,
+    connections: HashMap<u64, Connection>,
+    /* Local event channel port of each ring connection */
+    ports: HashMap<u32, u64>,
+    /* Raised by Xen when a domain dies, domains are polled without it */
+    virq_port: Option<u32>,
+}
+
+impl Daemon {
+    fn new(quota: XsQuota, listener: UnixListener) -> Self {
+        Daemon {
+            server: XenStoreServer::with_quota(quota),
+            listener,
+            evtchn: None,
+            connections: HashMap::new(),
+            ports: HashMap::new(),
+            virq_port: None,
+        }
+    }
+
+    /* Serve dom0's ring, found through the xenbus_backend device */
+    fn init_dom0(&mut self) -> Result<(), std::io::Error> {
+        let evtchn = XenEventChannelHandle::new()?;
+        evtchn.set_nonblocking(true)?;
+        let (ring, remote_port) = XenStoreRing::dom0()?;
+        let port = evtchn.bind_interdomain(0, remote_port)?;
+
+        /* The ring is already mapped, there is nothing more to do for it */
+        self.server.request(XsRequest::Introduce {
+            domid: 0,
+            mfn: 0,
+            port: remote_port,
+        })?;
+        self.server.take_domain_events();
+
+        match evtchn.bind_virq(VIRQ_DOM_EXC) {
+            Ok(port) => self.virq_port = Some(port),
+            Err(e) => eprintln!("xenstored: polling domains, no VIRQ_DOM_EXC: {}", e),
+        }
+
+        self.evtchn = Some(evtchn);
+        self.add_ring(ring, 0, port);
+
+        Ok(())
+    }
+
+    fn add_ring(&mut self, ring: XenStoreRing, domid: u32, port: u32) {
+        let conn_id = self.server.connect(domid);
+
+        self.connections.insert(
+            conn_id,
+            Connection::new(Transport::Ring { ring, domid, port }),
+        );
+        self.ports.insert(port, conn_id);
+
+        /* Requests may have been queued before we got there */
+        self.service(conn_id);
+    }
+
+    fn introduce(&mut self, domid: u32, mfn: u64, remote_port: u32) -> Result<(), std::io::Error> {
+        let evtchn = self
+            .evtchn
+            .as_ref()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::Unsupported))?;
+        let ring = XenStoreRing::foreign(domid, mfn)?;
+        let port = evtchn.bind_interdomain(domid, remote_port)?;
+
+        self.add_ring(ring, domid, port);
+
+        Ok(())
+    }
+
+    fn ring_connection(&self, domid: u32) -> Option<u64> {
+        self.connections
+            .iter()
+            .find(|(_, conn)| matches!(conn.transport, Transport::Ring { domid: d, .. } if d == domid))
+            .map(|(conn_id, _)| *conn_id)
+    }
+
+    fn drop_connection(&mut self, conn_id: u64) {
+        self.server.disconnect(conn_id);
+
+        if let Some(port) = self.connections.remove(&conn_id).and_then(|c| c.port()) {
+            self.ports.remove(&port);
+            if let Some(evtchn) = &self.evtchn {
+                let _ = evtchn.unbind(port);
+            }
+        }
+    }
+
+    fn notify(&self, conn_id: u64) {
+        if let (Some(evtchn), Some(port)) = (
+            &self.evtchn,
+            self.connections.get(&conn_id).and_then(|c| c.port()),
+        ) {
+            let _ = evtchn.notify(port);
+        }
+    }
+
+    /* Handle what came in on `conn_id` */
+    fn service(&mut self, conn_id: u64) {
+        let conn = match self.connections.get_mut(&conn_id) {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        match conn.receive() {
+            Ok((packets, notify)) => {
+                for packet in packets.iter() {
+                    self.server.process(conn_id, packet);
+                }
+                if notify {
+                    self.notify(conn_id);
+                }
+            }
+            Err(e) => {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    eprintln!("xenstored: dropping connection {}: {}", conn_id, e);
+                }
+                match conn.transport {
+                    /* A domain can't talk to us anymore, forget about it */
+                    Transport::Ring { domid, .. } => {
+                        let _ = self.server.release_domain(domid);
+                        self.drop_connection(conn_id);
+                    }
+                    Transport::Socket(_) => self.drop_connection(conn_id),
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        eprintln!("xenstored: can't set up connection: {}", e);
+                        continue;
+                    }
+                    /* Only root can get to the socket */
+                    let conn_id = self.server.connect(0);
+                    self.connections
+                        .insert(conn_id, Connection::new(Transport::Socket(stream)));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("xenstored: accept failed: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_domain_events(&mut self) -> bool {
+        let events = self.server.take_domain_events();
+
+        for event in events.iter() {
+            match *event {
+                XsDomainEvent::Introduced { domid, mfn, port } => {
+                    if let Err(e) = self.introduce(domid, mfn, port) {
+                        eprintln!("xenstored: can't connect to domain {}: {}", domid, e);
+                        let _ = self.server.release_domain(domid);
+                    }
+                }
+                XsDomainEvent::Released { domid } => {
+                    if let Some(conn_id) = self.ring_connection(domid) {
+                        self.drop_connection(conn_id);
+                    }
+                }
+            }
+        }
+
+        !events.is_empty()
+    }
+
+    /* Hand the replies and watch events over to the connections and push them out */
+    fn dispatch(&mut self) {
+        loop {
+            let outgoing = self.server.take_outgoing();
+            for (conn_id, packet) in outgoing.iter() {
+                if let Some(conn) = self.connections.get_mut(conn_id) {
+                    conn.send(packet);
+                }
+            }
+
+            if !self.handle_domain_events() && outgoing.is_empty() {
+                break;
+            }
+        }
+
+        let pending: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.wants_write())
+            .map(|(conn_id, _)| *conn_id)
+            .collect();
+        for conn_id in pending {
+            match self.connections.get_mut(&conn_id).unwrap().flush() {
+                Ok(true) => self.notify(conn_id),
+                Ok(false) => (),
+                Err(e) => {
+                    eprintln!("xenstored: dropping connection {}: {}", conn_id, e);
+                    self.drop_connection(conn_id);
+                }
+            }
+        }
+    }
+
+    /*
+     * Domains that were destroyed without the toolstack telling us.  As with
+     * the C xenstored, those that shut down or crashed keep their connection:
+     * they may resume, i.e after a save/restore or a migration.
+     */
+    fn check_domains(&mut self) {
+        for domid in self.server.introduced() {
+            if domid == 0 {
+                continue;
+            }
+
+            /* A failing query says nothing about the domain, it is kept */
+            match xc_domain_getinfo(domid as u16) {
+                Ok(Some(info)) if info.dying => {
+                    let _ = self.server.release_domain(domid);
+                }
+                Ok(Some(info)) if info.shutdown || info.crashed => {
+                    let _ = self.server.shutdown_domain(domid);
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    let _ = self.server.release_domain(domid);
+                }
+                Err(e) => eprintln!("xenstored: can't get info of domain {}: {}", domid, e),
+            }
+        }
+    }
+
+    /* Drain the ports raised since last time, the handle is non-blocking */
+    fn raised_ports(&self) -> Result<Vec<u32>, std::io::Error> {
+        let evtchn = self.evtchn.as_ref().unwrap();
+        let mut ports = [0; 64];
+        let mut raised = Vec::new();
+
+        loop {
+            match evtchn.pending_batch(&mut ports) {
+                Ok(0) => break,
+                Ok(count) => {
+                    for port in ports[..count].iter() {
+                        evtchn.unmask(*port)?;
+                        if !raised.contains(port) {
+                            raised.push(*port);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(raised)
+    }
+
+    fn run(&mut self) -> Result<(), std::io::Error> {
+        let mut last_check = Instant::now();
+
+        loop {
+            let socket_ids: Vec<u64> = self
+                .connections
+                .iter()
+                .filter(|(_, conn)| conn.fd().is_some())
+                .map(|(conn_id, _)| *conn_id)
+                .collect();
+
+            let mut poll_fds = vec![PollFd::new(self.listener.as_raw_fd(), PollFlags::POLLIN)];
+            if let Some(evtchn) = &self.evtchn {
+                poll_fds.push(PollFd::new(evtchn.fd()?, PollFlags::POLLIN));
+            }
+            let first_socket = poll_fds.len();
+            for conn_id in socket_ids.iter() {
+                let conn = &self.connections[conn_id];
+                let mut flags = PollFlags::POLLIN;
+                if conn.wants_write() {
+                    flags |= PollFlags::POLLOUT;
+                }
+                poll_fds.push(PollFd::new(conn.fd().unwrap(), flags));
+            }
+
+            match poll(&mut poll_fds, DOMAIN_CHECK_INTERVAL.as_millis() as i32) {
+                Ok(_) => (),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(std::io::Error::from(e)),
+            }
+
+            let ready: Vec<PollFlags> = poll_fds
+                .iter()
+                .map(|fd| fd.revents().unwrap_or_else(PollFlags::empty))
+                .collect();
+
+            if ready[0].contains(PollFlags::POLLIN) {
+                self.accept();
+            }
+
+            if first_socket == 2 && ready[1].contains(PollFlags::POLLIN) {
+                for port in self.raised_ports()? {
+                    if Some(port) == self.virq_port {
+                        self.check_domains();
+                    } else if let Some(conn_id) = self.ports.get(&port).copied() {
+                        self.service(conn_id);
+                    }
+                }
+            }
+
+            for (conn_id, revents) in socket_ids.iter().zip(ready[first_socket..].iter()) {
+                if revents.intersects(PollFlags::POLLIN | PollFlags::POLLHUP | PollFlags::POLLERR) {
+                    self.service(*conn_id);
+                }
+            }
+
+            if last_check.elapsed() >= DOMAIN_CHECK_INTERVAL {
+                last_check = Instant::now();
+                if self.evtchn.is_some() && self.virq_port.is_none() {
+                    self.check_domains();
+                }
+            }
+
+            self.dispatch();
+        }
+    }
+}
+
+fn bind(path: &str) -> Result<UnixListener, std::io::Error> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    /* Left behind by a previous instance */
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+fn main() {
+    let options = parse_options().unwrap_or_else(|e| {
+        eprintln!("xenstored: {}\n\n{}", e, USAGE);
+        process::exit(1);
+    });
+
+    let path = env::var(XENSTORED_PATH_ENV).unwrap_or_else(|_| String::from(XENSTORED_SOCKET));
+    let listener = bind(&path).unwrap_or_else(|e| {
+        eprintln!("xenstored: can't listen on {}: {}", path, e);
+        process::exit(1);
+    });
+
+    let mut daemon = Daemon::new(options.quota, listener);
+
+    if options.domain_init {
+        if let Err(e) = daemon.init_dom0() {
+            eprintln!("xenstored: serving the socket only, no dom0 ring: {}", e);
+        }
+    }
+
+    if let Err(e) = daemon.run() {
+        eprintln!("xenstored: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright 2022-23 Mathieu Poirier <mathieu.poirier@linaro.org>
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    convert::TryInto,
+    fs::OpenOptions,
+    io::{Error, ErrorKind},
+    os::unix::io::AsRawFd,
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{fence, Ordering},
+};
+
+use libc::{c_void, mmap, munmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+use vmm_sys_util::ioctl::_IOC_NONE;
+use xen_bindings::bindings::{xenstore_domain_interface, XENSTORE_RING_SIZE};
+use xen_ioctls::{xenforeignmemory_map, xenforeignmemory_unmap};
+
+const XENBUS_BACKEND_DEVICE: &str = "/dev/xen/xenbus_backend";
+const XENBUS_BACKEND_TYPE: u32 = 'B' as u32;
+const PAGE_SIZE: usize = 4096;
+
+/*
+ * #define IOCTL_XENBUS_BACKEND_EVTCHN \
+ *      _IOC(_IOC_NONE, 'B', 0, 0)
+ */
+ioctl_ioc_nr!(
+    IOCTL_XENBUS_BACKEND_EVTCHN,
+    _IOC_NONE,
+    XENBUS_BACKEND_TYPE,
+    0_u32,
+    0_u32
+);
+
+enum XenStoreRingMapping {
+    /* Dom0's page, from the xenbus_backend device */
+    Local,
+    /* Any other domain's page, through privcmd */
+    Foreign,
+}
+
+/* The shared page a domain uses to talk to xenstored */
+pub struct XenStoreRing {
+    interface: *mut xenstore_domain_interface,
+    mapping: XenStoreRingMapping,
+}
+
+impl XenStoreRing {
+    /* Dom0's ring and the port of its xenstore event channel */
+    pub fn dom0() -> Result<(Self, u32), std::io::Error> {
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(XENBUS_BACKEND_DEVICE)?;
+
+        // SAFETY: fd is a valid XENBUS_BACKEND_DEVICE descriptor and the
+        // IOCTL_XENBUS_BACKEND_EVTCHN ioctl doesn't take an argument
+        let port = match unsafe {
+            libc::ioctl(
+                fd.as_raw_fd(),
+                #[allow(clippy::useless_conversion)]
+                IOCTL_XENBUS_BACKEND_EVTCHN().try_into().unwrap(),
+            )
+        } {
+            ret if ret < 0 => return Err(Error::last_os_error()),
+            ret => ret as u32,
+        };
+
+        // SAFETY: we ask for a new shared mapping of the device's only page,
+        // the result is checked below
+        let addr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                PAGE_SIZE,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+
+        Ok((
+            XenStoreRing {
+                interface: addr.cast(),
+                mapping: XenStoreRingMapping::Local,
+            },
+            port,
+        ))
+    }
+
+    /* The ring of `domid`, as given to XS_INTRODUCE */
+    pub fn foreign(domid: u32, mfn: u64) -> Result<Self, std::io::Error> {
+        let domid: u16 = domid
+            .try_into()
+            .map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+        let mut err: libc::c_int = 0;
+
+        // SAFETY: `mfn` and `err` are valid for the one page being mapped
+        let addr = unsafe {
+            xenforeignmemory_map(
+                domid,
+                PROT_READ | PROT_WRITE,
+                1,
+                addr_of!(mfn),
+                addr_of_mut!(err),
+            )?
+        };
+        if err != 0 {
+            let _ = xenforeignmemory_unmap(addr, 1);
+            return Err(Error::from_raw_os_error(err.abs()));
+        }
+
+        Ok(XenStoreRing {
+            interface: addr.cast(),
+            mapping: XenStoreRingMapping::Foreign,
+        })
+    }
+
+    fn mask(idx: u32) -> usize {
+        (idx & (XENSTORE_RING_SIZE - 1)) as usize
+    }
+
+    /*
+     * Append whatever the domain has put in the request ring to `input`.  Returns
+     * true if something was consumed, in which case the domain should be
+     * notified.
+     */
+    pub fn read_requests(&mut self, input: &mut Vec<u8>) -> Result<bool, std::io::Error> {
+        let iface = self.interface;
+
+        // SAFETY: `iface` points to a mapped xenstore_domain_interface for as
+        // long as `self` lives.  The domain may change the indexes at any time,
+        // hence the volatile accesses.
+        unsafe {
+            let cons = std::ptr::read_volatile(addr_of!((*iface).req_cons));
+            let prod = std::ptr::read_volatile(addr_of!((*iface).req_prod));
+            fence(Ordering::SeqCst);
+
+            let avail = prod.wrapping_sub(cons);
+            if avail > XENSTORE_RING_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "request ring corrupted"));
+            }
+            if avail == 0 {
+                return Ok(false);
+            }
+
+            let req = addr_of!((*iface).req) as *const u8;
+            for i in 0..avail {
+                input.push(std::ptr::read_volatile(
+                    req.add(Self::mask(cons.wrapping_add(i))),
+                ));
+            }
+
+            fence(Ordering::SeqCst);
+            std::ptr::write_volatile(addr_of_mut!((*iface).req_cons), prod);
+        }
+
+        Ok(true)
+    }
+
+    /* Put as much of `output` as fits in the response ring, returns how much */
+    pub fn write_responses(&mut self, output: &[u8]) -> Result<usize, std::io::Error> {
+        let iface = self.interface;
+
+        // SAFETY: see read_requests()
+        unsafe {
+            let cons = std::ptr::read_volatile(addr_of!((*iface).rsp_cons));
+            let prod = std::ptr::read_volatile(addr_of!((*iface).rsp_prod));
+            fence(Ordering::SeqCst);
+
+            let used = prod.wrapping_sub(cons);
+            if used > XENSTORE_RING_SIZE {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "response ring corrupted",
+                ));
+            }
+
+            let len = output.len().min((XENSTORE_RING_SIZE - used) as usize);
+            let rsp = addr_of_mut!((*iface).rsp) as *mut u8;
+            for (i, byte) in output[..len].iter().enumerate() {
+                std::ptr::write_volatile(rsp.add(Self::mask(prod.wrapping_add(i as u32))), *byte);
+            }
+
+            fence(Ordering::SeqCst);
+            std::ptr::write_volatile(
+                addr_of_mut!((*iface).rsp_prod),
+                prod.wrapping_add(len as u32),
+            );
+
+            Ok(len)
+        }
+    }
+}
+
+impl Drop for XenStoreRing {
+    fn drop(&mut self) {
+        let addr: *mut c_void = self.interface.cast();
+
+        match self.mapping {
+            // SAFETY: `addr` was returned by mmap() for one page
+            XenStoreRingMapping::Local => unsafe {
+                munmap(addr, PAGE_SIZE);
+            },
+            XenStoreRingMapping::Foreign => {
+                let _ = xenforeignmemory_unmap(addr, 1);
+            }
+        }
+    }
+}